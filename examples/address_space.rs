@@ -0,0 +1,61 @@
+#![no_std]
+
+use os::memory::paging::{
+    virtual_to_physical, ActivePageTable, EntryFlags, InactivePageTable, TemporaryPage,
+};
+use os::memory::{
+    BitmapFrameAllocator, FrameAllocator, Page, VirtAddr, TEMPORARY_PAGE_REGION_START,
+};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    loop {}
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    let memory_regions = boot_info.memory_regions();
+    let mut allocator =
+        BitmapFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+    let mut active = unsafe { ActivePageTable::new() };
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtAddr::new(
+        TEMPORARY_PAGE_REGION_START,
+    )));
+
+    let frame = allocator.allocate().unwrap();
+    let mut table = InactivePageTable::new(frame, &mut temporary_page, &mut allocator).unwrap();
+
+    // Map page only in the inactive table
+    let address = VirtAddr::new(0xdeadbeaf);
+    let frame = allocator.allocate().unwrap();
+    active
+        .with(
+            &mut table,
+            &mut temporary_page,
+            &mut allocator,
+            |mapper, allocator| {
+                let page = Page::containing_address(address);
+                // `with` flushes the whole TLB when switching back
+                mapper
+                    .map_page_to_existing_frame(page, frame, EntryFlags::WRITABLE, allocator)
+                    .unwrap()
+                    .ignore();
+                serial_println!(
+                    "inactive: {}",
+                    mapper.virtual_to_physical(address) == Some(frame.start_address() + 0xeaf)
+                );
+            },
+        )
+        .unwrap();
+    serial_println!("active: {:?}", virtual_to_physical(address));
+
+    // Active table is intact
+    serial_println!("active: {:?}", virtual_to_physical(VirtAddr::new(0xb8000)));
+
+    qemu::exit_success();
+    loop {}
+}
@@ -0,0 +1,70 @@
+#![no_std]
+
+use os::memory::{BitmapFrameAllocator, Frame, FrameAllocator, PhysAddr};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    loop {}
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    let memory_regions = boot_info.memory_regions();
+    let mut allocator =
+        BitmapFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+    let free = allocator.free_frames();
+
+    #[cfg(not(os_test))]
+    serial_println!(
+        "total = {}, free = {}",
+        allocator.total_frames(),
+        allocator.free_frames()
+    );
+
+    // Freed frame is reused
+    let x = allocator.allocate().unwrap();
+    let y = allocator.allocate().unwrap();
+    let z = allocator.allocate().unwrap();
+    allocator.deallocate(y);
+    serial_println!("reuse = {}", allocator.allocate() == Some(y));
+    allocator.deallocate(x);
+    allocator.deallocate(y);
+    allocator.deallocate(z);
+    serial_println!(
+        "free after deallocate = {}",
+        allocator.free_frames() == free
+    );
+
+    // Device memory (e.g. local APIC) is outside managed frames
+    let device = Frame::containing_address(PhysAddr::new(0xfee0_0000));
+    serial_println!(
+        "device frame: used = {}, references = {}",
+        allocator.is_used(device),
+        allocator.reference_count(device)
+    );
+
+    let stats = allocator.frame_stats().unwrap();
+    serial_println!(
+        "stats = {}",
+        stats.free == free && stats.used() + stats.free == stats.total
+    );
+
+    // Exhaust and return everything
+    let mut count = 0;
+    while allocator.allocate().is_some() {
+        count += 1;
+    }
+    serial_println!("allocate all = {}", count == free);
+    serial_println!("exhausted = {:?}", allocator.allocate());
+    serial_println!(
+        "largest free run = {}",
+        allocator.frame_stats().unwrap().largest_free_run
+    );
+
+    qemu::exit_success();
+    loop {}
+}
@@ -0,0 +1,59 @@
+#![no_std]
+
+use os::memory::{BuddyFrameAllocator, Frame, FrameAllocator};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    loop {}
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    let memory_regions = boot_info.memory_regions();
+    let mut allocator =
+        BuddyFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+    let free = allocator.stats().free_frames;
+
+    #[cfg(not(os_test))]
+    serial_println!("{:?}", allocator.stats());
+
+    // 2MB block (e.g. for huge page)
+    let huge = allocator.allocate_contiguous(9).unwrap();
+    serial_println!("huge aligned = {}", huge.number() % (1 << 9) == 0);
+
+    // Single frames split larger blocks
+    let mut frames = [Frame::from_number(0); 64];
+    for frame in frames.iter_mut() {
+        *frame = allocator.allocate().unwrap();
+    }
+    serial_println!("split = {}", allocator.stats().splits > 0);
+
+    #[cfg(not(os_test))]
+    serial_println!("{:?}", allocator.stats());
+
+    // Shared frame is freed by its last reference
+    allocator.add_reference(frames[0]);
+    allocator.deallocate(frames[0]);
+    serial_println!(
+        "shared reference count = {}",
+        allocator.reference_count(frames[0])
+    );
+
+    // Everything merges back
+    for &frame in frames.iter() {
+        allocator.deallocate(frame);
+    }
+    allocator.free_contiguous(huge, 9);
+    serial_println!("merge = {}", allocator.stats().merges > 0);
+    serial_println!("free = {}", allocator.stats().free_frames == free);
+
+    #[cfg(not(os_test))]
+    serial_println!("{:?}", allocator.stats());
+
+    qemu::exit_success();
+    loop {}
+}
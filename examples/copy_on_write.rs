@@ -0,0 +1,114 @@
+#![no_std]
+#![feature(naked_functions)]
+#![feature(asm)]
+
+use os::asm::{read_cr0, write_cr0, CR0_WRITE_PROTECT};
+use os::idt::IDT;
+use os::memory::paging::{
+    make_copy_on_write, map_page_copy_on_write, map_page_to_frame, virtual_to_physical, EntryFlags,
+};
+use os::memory::vma::{set_page_fault_handler, VMAS};
+use os::memory::{BitmapFrameAllocator, FrameAllocator, Page, VirtAddr, PAGE_SIZE};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+use os::util::{address_cast, address_cast_mut, Mutex, Volatile};
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    qemu::exit_fail();
+    loop {}
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+const ORIGINAL: VirtAddr = VirtAddr::new_truncate(0x5000_0000);
+const COPY: VirtAddr = VirtAddr::new_truncate(0x5000_0000 + PAGE_SIZE);
+
+fn read(addr: VirtAddr) -> u64 {
+    unsafe { address_cast::<Volatile<u64>>(addr.as_u64() as usize).read() }
+}
+
+fn write(addr: VirtAddr, value: u64) {
+    unsafe { address_cast_mut::<Volatile<u64>>(addr.as_u64() as usize).write(value) }
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    set_page_fault_handler(&mut IDT.lock());
+
+    // Write to read only page has to fault even for kernel
+    write_cr0(read_cr0() | CR0_WRITE_PROTECT);
+
+    let memory_regions = boot_info.memory_regions();
+    *FRAME_ALLOCATOR.lock() = Some(BitmapFrameAllocator::new(
+        memory_regions.usable(),
+        memory_regions.occupied(),
+    ));
+    VMAS.lock().init(&FRAME_ALLOCATOR);
+
+    // NOTE: page fault handler locks the frame allocator, so it's not held across writes
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let frame = allocator.allocate().unwrap();
+        map_page_to_frame(
+            Page::containing_address(ORIGINAL),
+            frame,
+            flags,
+            &mut *allocator,
+        )
+        .unwrap()
+        .flush();
+    }
+    write(ORIGINAL, 42);
+
+    // Share the frame between two pages
+    let (frame, flags, flush) = make_copy_on_write(Page::containing_address(ORIGINAL)).unwrap();
+    flush.flush();
+    {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        map_page_copy_on_write(
+            Page::containing_address(COPY),
+            frame,
+            flags,
+            &mut *allocator,
+        )
+        .unwrap()
+        .flush();
+    }
+    serial_println!(
+        "shared = {}",
+        virtual_to_physical(ORIGINAL) == virtual_to_physical(COPY)
+    );
+    serial_println!(
+        "reference count = {}",
+        FRAME_ALLOCATOR.lock().reference_count(frame)
+    );
+    serial_println!("read copy = {}", read(COPY));
+
+    // Write to the copy gets its own frame
+    write(COPY, 7);
+    serial_println!("original = {}, copy = {}", read(ORIGINAL), read(COPY));
+    serial_println!(
+        "copied = {}",
+        virtual_to_physical(ORIGINAL) != virtual_to_physical(COPY)
+    );
+    serial_println!(
+        "reference count = {}",
+        FRAME_ALLOCATOR.lock().reference_count(frame)
+    );
+
+    // Write to the last reference keeps the frame
+    write(ORIGINAL, 43);
+    serial_println!("original = {}", read(ORIGINAL));
+    serial_println!(
+        "same frame = {}",
+        virtual_to_physical(ORIGINAL) == Some(frame.start_address())
+    );
+    serial_println!("handled faults = {}", VMAS.lock().handled_faults());
+
+    qemu::exit_success();
+    loop {}
+}
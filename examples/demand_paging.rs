@@ -0,0 +1,93 @@
+#![no_std]
+#![feature(naked_functions)]
+#![feature(asm)]
+#![feature(panic_info_message)]
+
+use os::asm::{read_cr0, write_cr0, CR0_WRITE_PROTECT};
+use os::idt::IDT;
+use os::memory::paging::{translate, EntryFlags};
+use os::memory::vma::{set_page_fault_handler, VMAS};
+use os::memory::{BitmapFrameAllocator, VirtAddr, PAGE_SIZE};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+use os::util::{address_cast, address_cast_mut, Mutex, Volatile};
+
+// Invalid access is expected to end up here (without source location to keep output stable)
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    if let Some(message) = info.message() {
+        serial_println!("panic: {}", message);
+    }
+    qemu::exit_success();
+    loop {}
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+const AREA_START: VirtAddr = VirtAddr::new_truncate(0x5000_0000);
+const AREA_END: VirtAddr = VirtAddr::new_truncate(0x5000_0000 + 16 * PAGE_SIZE);
+const READ_ONLY_START: VirtAddr = VirtAddr::new_truncate(0x5100_0000);
+const READ_ONLY_END: VirtAddr = VirtAddr::new_truncate(0x5100_0000 + PAGE_SIZE);
+
+fn read(addr: VirtAddr) -> u64 {
+    unsafe { address_cast::<Volatile<u64>>(addr.as_u64() as usize).read() }
+}
+
+fn write(addr: VirtAddr, value: u64) {
+    unsafe { address_cast_mut::<Volatile<u64>>(addr.as_u64() as usize).write(value) }
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    set_page_fault_handler(&mut IDT.lock());
+
+    let memory_regions = boot_info.memory_regions();
+    *FRAME_ALLOCATOR.lock() = Some(BitmapFrameAllocator::new(
+        memory_regions.usable(),
+        memory_regions.occupied(),
+    ));
+    // NOTE: page fault handler locks VMAS, so it's not held across accesses
+    {
+        let mut vmas = VMAS.lock();
+        vmas.init(&FRAME_ALLOCATOR);
+        let data_flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        vmas.register(AREA_START, AREA_END, data_flags).unwrap();
+        vmas.register(READ_ONLY_START, READ_ONLY_END, EntryFlags::NO_EXECUTE)
+            .unwrap();
+        serial_println!(
+            "overlap = {:?}",
+            vmas.register(AREA_START + PAGE_SIZE, AREA_END, data_flags)
+        );
+    }
+
+    // Nothing is mapped until the first access
+    serial_println!("before access = {:?}", translate(AREA_START));
+
+    // Read faults in zero-filled page
+    serial_println!("read = {}", read(AREA_START + 8));
+
+    // Write to every other page
+    for i in (0..16).step_by(2) {
+        write(AREA_START + i * PAGE_SIZE, i);
+    }
+    let sum: u64 = (0..16)
+        .step_by(2)
+        .map(|i| read(AREA_START + i * PAGE_SIZE))
+        .sum();
+    serial_println!("sum = {}", sum);
+    serial_println!("handled faults = {}", VMAS.lock().handled_faults());
+    serial_println!(
+        "after access = {}",
+        translate(AREA_START + 2 * PAGE_SIZE).is_some()
+    );
+
+    // Read only area can be read but not written (even by kernel)
+    write_cr0(read_cr0() | CR0_WRITE_PROTECT);
+    serial_println!("read only = {}", read(READ_ONLY_START));
+    write(READ_ONLY_START, 1);
+
+    serial_println!("unreachable");
+    qemu::exit_fail();
+    loop {}
+}
@@ -28,7 +28,6 @@ pub extern "C" fn kernel_main() -> ! {
     println!("hello vga");
     serial_println!("hello serial");
 
-    IDT.lock().load();
     IDT.lock()
         .set_handler(IdtIndex::Breakpoint, make_isr!(breakpoint_handler));
 
@@ -40,6 +39,6 @@ pub extern "C" fn kernel_main() -> ! {
     loop {}
 }
 
-extern "C" fn breakpoint_handler(arg: &IsrArg) {
+extern "C" fn breakpoint_handler(arg: &mut IsrArg) {
     serial_println!("BREAKPOINT: error_code = {}", { arg.error_code });
 }
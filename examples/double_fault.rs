@@ -32,7 +32,7 @@ pub extern "C" fn kernel_main() -> ! {
     loop {}
 }
 
-extern "C" fn double_fault_handler(arg: &IsrArg) {
+extern "C" fn double_fault_handler(arg: &mut IsrArg) {
     serial_println!("DOUBLE_FAULT: error_code = {}", { arg.error_code },);
     qemu::exit_success();
 }
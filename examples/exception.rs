@@ -0,0 +1,93 @@
+#![no_std]
+#![feature(naked_functions)]
+#![feature(asm)]
+#![feature(panic_info_message)]
+
+use os::asm::int3;
+use os::idt::{IdtIndex, IsrArg, IDT};
+use os::make_isr;
+use os::qemu;
+use os::serial_println;
+
+// Unhandled exception is expected to end up here (without source location to keep output stable)
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    if let Some(message) = info.message() {
+        #[cfg(not(os_test))]
+        serial_println!("panic: {}", message);
+
+        #[cfg(os_test)]
+        {
+            use core::fmt::Write;
+            os::serial_print!("panic: ");
+            FirstLine(false).write_fmt(*message).unwrap();
+            serial_println!();
+        }
+    }
+    qemu::exit_success();
+    loop {}
+}
+
+// Drop the rest of report since register values vary by build
+#[cfg(os_test)]
+struct FirstLine(bool);
+
+#[cfg(os_test)]
+impl core::fmt::Write for FirstLine {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if !self.0 {
+            match s.find('\n') {
+                Some(i) => {
+                    os::serial_print!("{}", &s[..i]);
+                    self.0 = true;
+                }
+                None => os::serial_print!("{}", s),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main() -> ! {
+    IDT.lock().set_default_handlers();
+
+    // Default handler can be overridden
+    IDT.lock()
+        .set_handler(IdtIndex::Breakpoint, make_isr!(breakpoint_handler));
+    int3();
+
+    serial_println!(
+        "error code: PageFault = {}, InvalidOpcode = {}",
+        IdtIndex::PageFault.has_error_code(),
+        IdtIndex::InvalidOpcode.has_error_code()
+    );
+    serial_println!("vector 15 = {:?}", IdtIndex::from_vector(15));
+
+    // Handler skips `ud2` and sets rax
+    IDT.lock()
+        .set_handler(IdtIndex::InvalidOpcode, make_isr!(invalid_opcode_handler));
+    let rax: u64;
+    unsafe {
+        asm!("ud2", inout("rax") 0u64 => rax);
+    }
+    serial_println!("AFTER ud2: rax = {}", rax);
+
+    // Selector beyond GDT limit
+    unsafe {
+        asm!("mov ds, {0:x}", in(reg) 0x1230);
+    }
+    serial_println!("AFTER general protection fault");
+
+    loop {}
+}
+
+extern "C" fn breakpoint_handler(arg: &mut IsrArg) {
+    serial_println!("BREAKPOINT: error_code = {}", { arg.error_code });
+}
+
+extern "C" fn invalid_opcode_handler(arg: &mut IsrArg) {
+    serial_println!("INVALID_OPCODE: cs = 0x{:x}", { arg.cs });
+    arg.rip += 2; // length of `ud2`
+    arg.rax = 42;
+}
@@ -1,6 +1,6 @@
 #![no_std]
 
-use os::memory::{FrameAllocator, SimpleFrameAllocator};
+use os::memory::{FrameAllocator, SimpleFrameAllocator, PAGE_SIZE};
 use os::multiboot2::BootInfo;
 use os::qemu;
 use os::serial_println;
@@ -13,26 +13,51 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
 #[no_mangle]
 pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    let memory_regions = boot_info.memory_regions();
+
     #[cfg(not(os_test))]
     {
         serial_println!("usable memory");
-        for (lo, hi) in boot_info.usable_memory() {
+        for (lo, hi) in memory_regions.usable() {
             serial_println!("   (0x{:08x}, 0x{:08x})", lo, hi);
         }
 
         serial_println!("occupied memory");
-        for (lo, hi) in boot_info.occupied_memory() {
+        for (lo, hi) in memory_regions.occupied() {
             serial_println!("   (0x{:08x}, 0x{:08x})", lo, hi);
         }
     }
 
+    // Checked against the memory map rather than printed, since frame numbers depend on
+    // the size of the kernel image
     let mut frame_allocator =
-        SimpleFrameAllocator::new(boot_info.usable_memory(), boot_info.occupied_memory());
-    while let Some(index) = frame_allocator.allocate() {
-        if index % 1000 == 0 {
-            serial_println!("{}", index);
-        }
+        SimpleFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+    let first = frame_allocator.allocate().unwrap();
+    let (mut count, mut increasing, mut overlapping) = (1, true, false);
+    let mut prev = first;
+    while let Some(frame) = frame_allocator.allocate() {
+        let lo = frame.start_address().as_u64();
+        let hi = lo + PAGE_SIZE;
+        increasing &= prev < frame;
+        overlapping |= memory_regions
+            .occupied()
+            .any(|(o_lo, o_hi)| o_lo < hi && lo < o_hi);
+        prev = frame;
+        count += 1;
     }
+    // Frame 0 is never allocated
+    let usable: u64 = memory_regions
+        .usable()
+        .map(|(lo, hi)| {
+            let first = core::cmp::max((lo + PAGE_SIZE - 1) / PAGE_SIZE, 1);
+            (hi / PAGE_SIZE).saturating_sub(first)
+        })
+        .sum();
+    serial_println!("first = {}", first.number());
+    serial_println!("increasing = {}", increasing);
+    serial_println!("occupied skipped = {}", !overlapping);
+    serial_println!("all usable allocated = {}", count == usable);
+    serial_println!("exhausted = {:?}", frame_allocator.allocate());
 
     qemu::exit_success();
     loop {}
@@ -6,13 +6,12 @@
 use os::asm::read_cr2;
 use os::idt::{IdtIndex, IsrArg, IDT};
 use os::make_isr;
-use os::memory::heap_allocator::MutexSimpleHeapAllocator;
-use os::memory::paging::{map_page_range, virtual_to_page};
-use os::memory::SimpleFrameAllocator;
-use os::memory::VirtualAddress;
+use os::memory::heap_allocator::MutexLinkedListHeapAllocator;
+use os::memory::BitmapFrameAllocator;
 use os::multiboot2::BootInfo;
 use os::qemu;
 use os::serial_println;
+use os::util::Mutex;
 
 extern crate alloc;
 use alloc::vec::Vec;
@@ -24,23 +23,23 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
 #[no_mangle]
 pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
     // Set page fault handler
-    IDT.lock().load();
     IDT.lock().set_handler(
         IdtIndex::PageFault,
         make_isr!(page_fault_handler, has_error_code),
     );
 
-    // Initialize heap memory
-    let mut allocator =
-        SimpleFrameAllocator::new(boot_info.usable_memory(), boot_info.occupied_memory());
-    map_page_range(
-        virtual_to_page(HEAP_START),
-        virtual_to_page(HEAP_END),
-        &mut allocator,
-    );
+    let memory_regions = boot_info.memory_regions();
+    // Heap memory is mapped on demand
+    *FRAME_ALLOCATOR.lock() = Some(BitmapFrameAllocator::new(
+        memory_regions.usable(),
+        memory_regions.occupied(),
+    ));
+    HEAP_ALLOCATOR.init(&FRAME_ALLOCATOR);
 
     // Instantiate vector
     #[cfg(not(heap_fail))]
@@ -51,11 +50,35 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
 
     serial_println!("sum = {}", xs.iter().sum::<usize>());
 
+    // Allocate and free repeatedly (freed memory has to be reused)
+    let mut total = 0;
+    for i in 0..1000 {
+        let ys: Vec<usize> = (0..(i % 100)).collect();
+        total += ys.len();
+    }
+    serial_println!("total = {}", total);
+
+    // Grow beyond 16KB
+    let zs: Vec<u8> = (0..(1 << 15)).map(|i| (i % 2) as u8).collect();
+    serial_println!("sum = {}", zs.iter().map(|&z| z as usize).sum::<usize>());
+    serial_println!("grown = {}", HEAP_ALLOCATOR.size() > (1 << 14));
+
+    let stats = HEAP_ALLOCATOR.stats();
+    serial_println!("used = {}", stats.used() >= zs.len() + xs.len() * 8);
+    drop(xs);
+    drop(zs);
+    let stats = HEAP_ALLOCATOR.stats();
+    serial_println!(
+        "after drop: used = {}, free blocks = {}",
+        stats.used(),
+        stats.free_blocks
+    );
+
     qemu::exit_success();
     loop {}
 }
 
-extern "C" fn page_fault_handler(arg: &IsrArg) {
+extern "C" fn page_fault_handler(arg: &mut IsrArg) {
     serial_println!(
         "PAGE_FAULT: error_code = {}, cr2 = 0x{:08x}",
         { arg.error_code },
@@ -68,12 +91,12 @@ extern "C" fn page_fault_handler(arg: &IsrArg) {
 // Rust heap allocator interface
 //
 
-const HEAP_START: VirtualAddress = 0x0100_0000;
-const HEAP_END: VirtualAddress = HEAP_START + (1 << 14); // 16KB = 4 pages
+const HEAP_START: usize = 0x4000_0000; // right after the boot time identity map
+const HEAP_CEILING: usize = HEAP_START + (1 << 16); // 64KB = 16 pages
 
 #[global_allocator]
-static HEAP_ALLOCATOR: MutexSimpleHeapAllocator =
-    MutexSimpleHeapAllocator::new(HEAP_START as usize, HEAP_END as usize);
+static HEAP_ALLOCATOR: MutexLinkedListHeapAllocator =
+    MutexLinkedListHeapAllocator::growable(HEAP_START, HEAP_CEILING);
 
 #[alloc_error_handler]
 pub fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
@@ -0,0 +1,105 @@
+#![no_std]
+
+use os::memory::paging::{
+    map_huge_page_to_frame, map_page_to_frame, translate, unmap_huge_page, unmap_page, EntryFlags,
+    PageSize,
+};
+use os::memory::{BuddyFrameAllocator, Page, VirtAddr};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+use os::util::{address_cast, address_cast_mut, Volatile};
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    loop {}
+}
+
+const HUGE_2MIB: VirtAddr = VirtAddr::new_truncate(0x4000_0000); // right after the boot time identity map
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    let memory_regions = boot_info.memory_regions();
+    let mut allocator =
+        BuddyFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+
+    // 2MB page backed by contiguous frames
+    let frame = allocator.allocate_contiguous(9).unwrap();
+    let page = Page::containing_address(HUGE_2MIB);
+    map_huge_page_to_frame(
+        page,
+        frame,
+        PageSize::Size2MiB,
+        EntryFlags::WRITABLE,
+        &mut allocator,
+    )
+    .unwrap()
+    .flush();
+    let (phys_addr, size) = translate(HUGE_2MIB + 0x12345).unwrap();
+    serial_println!(
+        "2MB translate = {}, {:?}",
+        phys_addr == frame.start_address() + 0x12345,
+        size
+    );
+
+    let address = (HUGE_2MIB + 0x1f_ffff).as_u64() as usize;
+    unsafe {
+        address_cast_mut::<Volatile<u8>>(address).write(1);
+    }
+    serial_println!("2MB write and read = {}", unsafe {
+        address_cast::<Volatile<u8>>(address).read()
+    });
+
+    // Mapping errors
+    serial_println!(
+        "2MB map again = {:?}",
+        map_huge_page_to_frame(
+            page,
+            frame,
+            PageSize::Size2MiB,
+            EntryFlags::WRITABLE,
+            &mut allocator
+        )
+        .err()
+    );
+    serial_println!(
+        "4KB map inside 2MB = {:?}",
+        map_page_to_frame(page + 1, frame, EntryFlags::WRITABLE, &mut allocator).err()
+    );
+    serial_println!("4KB unmap inside 2MB = {:?}", unmap_page(page + 1).err());
+
+    let (unmapped, flush) = unmap_huge_page(page, PageSize::Size2MiB).unwrap();
+    flush.flush();
+    serial_println!("2MB unmap = {}", unmapped == frame);
+    serial_println!(
+        "2MB unmap again = {:?}",
+        unmap_huge_page(page, PageSize::Size2MiB).err()
+    );
+    allocator.free_contiguous(frame, 9);
+    serial_println!("2MB translate after unmap = {:?}", translate(HUGE_2MIB));
+
+    // 1GB page aliasing the first 1GB of physical memory
+    #[cfg(not(os_test))]
+    if os::memory::paging::is_1gib_page_supported() {
+        const HUGE_1GIB: VirtAddr = VirtAddr::new_truncate(0x80_0000_0000); // 2nd p4 entry
+        let page = Page::containing_address(HUGE_1GIB);
+        map_huge_page_to_frame(
+            page,
+            os::memory::Frame::from_number(0),
+            PageSize::Size1GiB,
+            EntryFlags::WRITABLE,
+            &mut allocator,
+        )
+        .unwrap()
+        .flush();
+        serial_println!("1GB translate = {:x?}", translate(HUGE_1GIB + 0xb8000));
+        let (_, flush) = unmap_huge_page(page, PageSize::Size1GiB).unwrap();
+        flush.flush();
+    } else {
+        serial_println!("1GB page is not supported");
+    }
+
+    qemu::exit_success();
+    loop {}
+}
@@ -55,7 +55,7 @@ pub extern "C" fn kernel_main() -> ! {
 
 static mut COUNTER: usize = 1;
 
-extern "C" fn breakpoint_handler(arg: &IsrArg) {
+extern "C" fn breakpoint_handler(arg: &mut IsrArg) {
     serial_println!(
         "BREAKPOINT: error_code = {}, counter = {}",
         { arg.error_code },
@@ -66,7 +66,7 @@ extern "C" fn breakpoint_handler(arg: &IsrArg) {
     }
 }
 
-extern "C" fn page_fault_handler(arg: &IsrArg) {
+extern "C" fn page_fault_handler(arg: &mut IsrArg) {
     serial_println!(
         "PAGE_FAULT: error_code = {}, cr2 = 0x{:08x}",
         { arg.error_code },
@@ -0,0 +1,71 @@
+#![no_std]
+
+use os::asm::{hlt, int3, sti};
+use os::idt::{IdtIndex, IDT};
+use os::interrupts::{self, InterruptContext};
+use os::pic::Pic;
+use os::qemu;
+use os::serial_println;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    loop {}
+}
+
+const TIMER_IRQ: u8 = 0;
+
+#[no_mangle]
+pub extern "C" fn kernel_main() -> ! {
+    interrupts::init(&mut IDT.lock());
+    Pic::new().init();
+
+    // Non-capturing closure works as handler too
+    interrupts::register(IdtIndex::Breakpoint as u8, |context| {
+        serial_println!(
+            "BREAKPOINT: vector = {}, error_code = {}",
+            context.vector,
+            { context.error_code }
+        );
+    });
+    int3();
+
+    interrupts::register_irq(TIMER_IRQ, timer_handler);
+    sti();
+    while unsafe { TIMER_COUNTER } < 3 {
+        hlt();
+    }
+
+    // Timer keeps firing without handler (EOI is still sent)
+    for _ in 0..3 {
+        hlt();
+    }
+    serial_println!("ticks without handler");
+
+    interrupts::register_irq(TIMER_IRQ, |context| {
+        serial_println!("TIMER again: irq = {:?}", context.irq());
+        qemu::exit_success();
+    });
+    loop {
+        hlt();
+    }
+}
+
+static mut TIMER_COUNTER: usize = 0;
+
+fn timer_handler(context: &mut InterruptContext) {
+    unsafe {
+        TIMER_COUNTER += 1;
+        serial_println!(
+            "TIMER: vector = {}, counter = {}",
+            context.vector,
+            TIMER_COUNTER
+        );
+        if TIMER_COUNTER == 3 {
+            serial_println!(
+                "unregistered = {}",
+                interrupts::unregister_irq(TIMER_IRQ).is_some()
+            );
+        }
+    }
+}
@@ -35,12 +35,12 @@ pub extern "C" fn kernel_main() -> ! {
     loop {}
 }
 
-extern "C" fn timer_handler(_arg: &IsrArg) {
+extern "C" fn timer_handler(_arg: &mut IsrArg) {
     // no-op
     pic1_eoi();
 }
 
-extern "C" fn keyboard_handler(_arg: &IsrArg) {
+extern "C" fn keyboard_handler(_arg: &mut IsrArg) {
     let code = inb(keyboard::PORT);
     let release = code & 0x80 != 0;
     let byte = keyboard::TABLE[(code & !0x80) as usize];
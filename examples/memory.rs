@@ -2,11 +2,11 @@
 #![feature(naked_functions)]
 #![feature(asm)]
 
-use os::asm::{flush_tlb, read_cr2};
+use os::asm::read_cr2;
 use os::idt::{IdtIndex, IsrArg, IDT};
 use os::make_isr;
-use os::memory::paging::{map_page_to_frame, unmap_page, virtual_to_page, virtual_to_physical};
-use os::memory::{FrameAllocator, SimpleFrameAllocator};
+use os::memory::paging::{map_page_to_frame, unmap_page, virtual_to_physical, EntryFlags};
+use os::memory::{FrameAllocator, Page, SimpleFrameAllocator, VirtAddr};
 use os::multiboot2::BootInfo;
 use os::qemu;
 use os::serial_println;
@@ -24,7 +24,6 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
     // Exception handler
     //
 
-    IDT.lock().load();
     IDT.lock().set_handler(
         IdtIndex::PageFault,
         make_isr!(page_fault_handler, has_error_code),
@@ -34,8 +33,8 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
     // Paging manipulation
     //
 
-    let address = 0xdeadbeaf;
-    let page = virtual_to_page(address);
+    let address = VirtAddr::new(0xdeadbeaf);
+    let page = Page::containing_address(address);
 
     serial_println!("BEFORE map_page_to_frame");
     serial_println!(
@@ -43,10 +42,13 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
         virtual_to_physical(address)
     );
 
+    let memory_regions = boot_info.memory_regions();
     let mut allocator =
-        SimpleFrameAllocator::new(boot_info.usable_memory(), boot_info.occupied_memory());
+        SimpleFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
     let frame = allocator.allocate().unwrap();
-    map_page_to_frame(page, frame, &mut allocator);
+    map_page_to_frame(page, frame, EntryFlags::WRITABLE, &mut allocator)
+        .unwrap()
+        .flush();
 
     serial_println!("AFTER map_page_to_frame");
     serial_println!(
@@ -56,27 +58,28 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
 
     serial_println!("WRITE AND READ");
     unsafe {
-        address_cast_mut::<Volatile<u8>>(address as usize).write(1);
+        address_cast_mut::<Volatile<u8>>(address.as_u64() as usize).write(1);
     }
     serial_println!("*0xdeadbeaf = {}", unsafe {
-        address_cast::<Volatile<u8>>(address as usize).read()
+        address_cast::<Volatile<u8>>(address.as_u64() as usize).read()
     });
 
     serial_println!("BEFORE unmap_page");
-    unmap_page(page);
+    // SimpleFrameAllocator cannot take the frame back
+    let (_, flush) = unmap_page(page).unwrap();
+    flush.flush();
     serial_println!("AFTER unmap_page");
-    flush_tlb();
 
     serial_println!("WRITE");
     unsafe {
-        address_cast_mut::<Volatile<u8>>(address as usize).write(2);
+        address_cast_mut::<Volatile<u8>>(address.as_u64() as usize).write(2);
     }
 
     qemu::exit_success();
     loop {}
 }
 
-extern "C" fn page_fault_handler(arg: &IsrArg) {
+extern "C" fn page_fault_handler(arg: &mut IsrArg) {
     serial_println!(
         "PAGE_FAULT: error_code = {}, cr2 = 0x{:08x}",
         { arg.error_code },
@@ -0,0 +1,67 @@
+#![no_std]
+
+use os::memory::mmio::{map_mmio, map_mmio_with, unmap_mmio, CacheType};
+use os::memory::paging::{get_page_entry, translate, EntryFlags};
+use os::memory::{BitmapFrameAllocator, Page, PhysAddr};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+use os::util::{address_cast, address_cast_mut, Volatile};
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    qemu::exit_fail();
+    loop {}
+}
+
+const VGA_BUFFER: u64 = 0xb8000;
+const LOCAL_APIC: u64 = 0xfee0_0000; // beyond the boot time identity map
+const LOCAL_APIC_VERSION: u64 = 0x30;
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    let memory_regions = boot_info.memory_regions();
+    let mut allocator =
+        BitmapFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+    let free = allocator.free_frames();
+
+    // VGA text buffer through another mapping
+    let vga = map_mmio(PhysAddr::new(VGA_BUFFER), 80 * 25 * 2, &mut allocator).unwrap();
+    let flags = get_page_entry(Page::containing_address(vga))
+        .unwrap()
+        .flags();
+    serial_println!(
+        "uncached = {}",
+        flags.contains(EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH)
+    );
+    unsafe {
+        address_cast_mut::<Volatile<u16>>(vga.as_u64() as usize).write(0x0f41);
+    }
+    serial_println!("vga alias = {}", unsafe {
+        address_cast::<Volatile<u16>>(VGA_BUFFER as usize).read() == 0x0f41
+    });
+
+    // Local APIC registers (unaligned base keeps its offset)
+    let apic = map_mmio_with(
+        PhysAddr::new(LOCAL_APIC + LOCAL_APIC_VERSION),
+        4,
+        CacheType::Uncached,
+        &mut allocator,
+    )
+    .unwrap();
+    let version = unsafe { address_cast::<Volatile<u32>>(apic.as_u64() as usize).read() };
+    serial_println!("apic version = 0x{:x}", version & 0xff);
+
+    // Device frames are not taken from the allocator
+    serial_println!(
+        "frames untouched = {}",
+        free - allocator.free_frames() <= 2 // only for new page tables
+    );
+
+    unmap_mmio(vga, 80 * 25 * 2);
+    serial_println!("unmapped = {:?}", translate(vga));
+
+    qemu::exit_success();
+    loop {}
+}
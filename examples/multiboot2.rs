@@ -1,5 +1,6 @@
 #![no_std]
 
+use os::memory::{virt_to_phys, PhysAddr, VirtAddr};
 use os::multiboot2::BootInfo;
 use os::qemu;
 use os::serial_println;
@@ -16,8 +17,8 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
 
     #[cfg(not(os_test))]
     {
-        let addr = boot_info as *const _ as u32;
-        serial_println!("boot_info address = 0x{:08x}", addr);
+        let addr = boot_info as *const _ as u64;
+        serial_println!("boot_info address = 0x{:016x}", addr);
     }
 
     for (tag, _addr) in boot_info.tags() {
@@ -32,6 +33,11 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
 
     let memory_map = boot_info.memory_map().unwrap();
     let section_headers = boot_info.section_headers().unwrap();
+    let memory_regions = boot_info.memory_regions();
+    let kind_at = |addr: PhysAddr| {
+        let region = memory_regions.regions().iter().find(|r| r.contains(addr));
+        region.map(|r| r.kind)
+    };
 
     #[cfg(not(os_test))]
     {
@@ -59,13 +65,23 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
             });
         }
 
+        serial_println!("memory regions");
+        for r in memory_regions.regions() {
+            serial_println!(
+                "   (0x{:08x}, 0x{:08x}) {:?}",
+                r.start.as_u64(),
+                r.end.as_u64(),
+                r.kind
+            );
+        }
+
         serial_println!("usable memory");
-        for (lo, hi) in boot_info.usable_memory() {
+        for (lo, hi) in memory_regions.usable() {
             serial_println!("   (0x{:08x}, 0x{:08x})", lo, hi);
         }
 
         serial_println!("occupied memory");
-        for (lo, hi) in boot_info.occupied_memory() {
+        for (lo, hi) in memory_regions.occupied() {
             serial_println!("   (0x{:08x}, 0x{:08x})", lo, hi);
         }
     }
@@ -81,6 +97,13 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
         });
     }
 
+    let kernel = virt_to_phys(VirtAddr::from_ptr(kernel_main as *const u8)).unwrap();
+    serial_println!("kernel: {:?}", kind_at(kernel));
+    let boot_info_address = virt_to_phys(VirtAddr::from_ptr(boot_info)).unwrap();
+    serial_println!("boot info: {:?}", kind_at(boot_info_address));
+    serial_println!("framebuffer: {:?}", kind_at(PhysAddr::new(0xb8000)));
+    serial_println!("first page: {:?}", kind_at(PhysAddr::new(0)));
+
     qemu::exit_success();
     loop {}
 }
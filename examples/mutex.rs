@@ -0,0 +1,119 @@
+#![no_std]
+#![feature(panic_info_message)]
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+use os::asm::{read_rflags, sti, RFLAGS_IF};
+use os::idt::IDT;
+use os::interrupts;
+use os::pic::Pic;
+use os::qemu;
+use os::serial_println;
+use os::uart::SERIAL;
+use os::util::{IrqSafeMutex, Mutex};
+
+// Deadlock is expected to end up here
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Deadlock may be in the printer itself
+    unsafe { SERIAL.force_unlock() };
+    if let Some(message) = info.message() {
+        let mut text = Buffer::new();
+        text.write_fmt(*message).unwrap();
+        os::serial_print!("panic: ");
+        WithoutDigits.write_str(text.as_str()).unwrap();
+        serial_println!();
+
+        let mut owner = Buffer::new();
+        let line = FIRST_LOCK_LINE.load(Ordering::Relaxed);
+        write!(owner, "locked at {}:{}:", file!(), line).unwrap();
+        serial_println!(
+            "owner is first lock = {}",
+            text.as_str().contains(owner.as_str())
+        );
+    }
+    qemu::exit_success();
+    loop {}
+}
+
+// Formatted message is kept to compare the reported owner with `FIRST_LOCK_LINE`
+struct Buffer {
+    data: [u8; 128],
+    len: usize,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Self {
+            data: [0; 128],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.data[..self.len]).unwrap()
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.data.len() {
+            return Err(core::fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// Drop line and column of the reported owner to keep output stable when this file is edited
+struct WithoutDigits;
+
+impl Write for WithoutDigits {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars().filter(|c| !c.is_ascii_digit()) {
+            os::serial_print!("{}", c);
+        }
+        Ok(())
+    }
+}
+
+static COUNTER: Mutex<u64> = Mutex::new(0);
+static FIRST_LOCK_LINE: AtomicU32 = AtomicU32::new(0);
+static IRQ_COUNTER: IrqSafeMutex<u64> = IrqSafeMutex::new(0);
+
+fn interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_IF != 0
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main() -> ! {
+    // Guard releases the lock when dropped
+    {
+        let mut counter = COUNTER.lock();
+        *counter += 1;
+        serial_println!("try_lock while locked = {}", COUNTER.try_lock().is_none());
+    }
+    serial_println!("counter = {}", *COUNTER.try_lock().unwrap());
+
+    // Interrupts are disabled only while IrqSafeMutex is held
+    interrupts::init(&mut IDT.lock());
+    Pic::new().init();
+    sti();
+    {
+        let mut counter = IRQ_COUNTER.lock();
+        *counter += 1;
+        serial_println!("interrupts while locked = {}", interrupts_enabled());
+    }
+    serial_println!("interrupts after unlock = {}", interrupts_enabled());
+
+    // Locking again what is already held never succeeds without threads
+    FIRST_LOCK_LINE.store(line!() + 1, Ordering::Relaxed);
+    let _counter = COUNTER.lock();
+    let _again = COUNTER.lock();
+
+    serial_println!("unreachable");
+    qemu::exit_fail();
+    loop {}
+}
@@ -1,9 +1,13 @@
 #![no_std]
 
-use os::memory::paging::{get_child_table, get_p4_table, virtual_to_physical, PRESENT};
+use os::memory::paging::{
+    dump_page_table, get_child_table, get_p4_table, translate, virtual_to_physical,
+};
+use os::memory::VirtAddr;
 use os::multiboot2::BootInfo;
 use os::qemu;
 use os::serial_println;
+use os::uart::SERIAL;
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -16,7 +20,7 @@ pub extern "C" fn kernel_main(_boot_info: &BootInfo) -> ! {
     let p4 = get_p4_table();
     serial_println!("present p4 entry");
     for (i, &entry) in p4.iter().enumerate() {
-        if entry & PRESENT != 0 {
+        if entry.is_present() {
             serial_println!("   {}", i);
         }
     }
@@ -24,32 +28,45 @@ pub extern "C" fn kernel_main(_boot_info: &BootInfo) -> ! {
     let p3 = get_child_table(p4, 0).unwrap();
     serial_println!("present p3 entry");
     for (i, &entry) in p3.iter().enumerate() {
-        if entry & PRESENT != 0 {
+        if entry.is_present() {
             serial_println!("   {}", i);
         }
     }
 
     let p2 = get_child_table(p3, 0).unwrap();
-    serial_println!("present p2 entry (% 100)");
+    serial_println!("huge p2 entry (% 100)");
     for (i, &entry) in p2.iter().enumerate() {
-        if i % 100 == 0 && entry & PRESENT != 0 {
-            serial_println!("   {}", i);
-        }
-    }
-
-    let p1 = get_child_table(p2, 0).unwrap();
-    serial_println!("present p1 entry (% 100)");
-    for (i, &entry) in p1.iter().enumerate() {
-        if i % 100 == 0 && entry & PRESENT != 0 {
+        if i % 100 == 0 && entry.is_huge() {
             serial_println!("   {}", i);
         }
     }
 
     serial_println!("virtual_to_physical");
-    serial_println!("   0           ==> {:?}", virtual_to_physical(0));
-    serial_println!("   1 << 30 - 1 ==> {:?}", virtual_to_physical(1 << 30 - 1));
-    serial_println!("   1 << 30     ==> {:?}", virtual_to_physical(1 << 30));
-    serial_println!("   0xdeadbeaf  ==> {:?}", virtual_to_physical(0xdeadbeaf));
+    serial_println!(
+        "   0           ==> {:?}",
+        virtual_to_physical(VirtAddr::new(0))
+    );
+    serial_println!(
+        "   1 << 30 - 1 ==> {:?}",
+        virtual_to_physical(VirtAddr::new(1 << 30 - 1))
+    );
+    serial_println!(
+        "   1 << 30     ==> {:?}",
+        virtual_to_physical(VirtAddr::new(1 << 30))
+    );
+    serial_println!(
+        "   0xdeadbeaf  ==> {:?}",
+        virtual_to_physical(VirtAddr::new(0xdeadbeaf))
+    );
+
+    serial_println!("translate");
+    serial_println!(
+        "   0x12345678  ==> {:x?}",
+        translate(VirtAddr::new(0x12345678))
+    );
+
+    serial_println!("mapped ranges");
+    dump_page_table(&mut *SERIAL.lock()).unwrap();
 
     qemu::exit_success();
     loop {}
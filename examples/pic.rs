@@ -36,13 +36,13 @@ pub extern "C" fn kernel_main() -> ! {
     loop {}
 }
 
-extern "C" fn breakpoint_handler(arg: &IsrArg) {
+extern "C" fn breakpoint_handler(arg: &mut IsrArg) {
     serial_println!("BREAKPOINT: error_code = {}", { arg.error_code });
 }
 
 static mut TIMER_COUNTER: usize = 1;
 
-extern "C" fn timer_handler(arg: &IsrArg) {
+extern "C" fn timer_handler(arg: &mut IsrArg) {
     serial_println!(
         "TIMER: error_code = {}, counter = {}",
         { arg.error_code },
@@ -0,0 +1,70 @@
+#![no_std]
+#![feature(naked_functions)]
+#![feature(asm)]
+
+use os::idt::{IdtIndex, IsrArg, IDT};
+use os::make_isr;
+use os::memory::paging::{remap_kernel, virtual_to_physical, ActivePageTable};
+use os::memory::{phys_to_virt, BitmapFrameAllocator, FrameAllocator, PhysAddr, VirtAddr};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+use os::util::Volatile;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    loop {}
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    IDT.lock().set_handler(
+        IdtIndex::PageFault,
+        make_isr!(page_fault_handler, has_error_code),
+    );
+
+    let memory_regions = boot_info.memory_regions();
+    let mut allocator =
+        BitmapFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+
+    serial_println!("BEFORE remap_kernel");
+    let mut active = unsafe { ActivePageTable::new() };
+    remap_kernel(&mut active, boot_info, core::iter::empty(), &mut allocator).unwrap();
+    serial_println!("AFTER remap_kernel");
+
+    // Allocator storage is reached through the physical memory mapping, which is kept
+    serial_println!(
+        "allocate after remap_kernel = {}",
+        allocator.allocate().is_some()
+    );
+
+    // Boot time identity map is gone
+    serial_println!(
+        "virtual_to_physical(0x20000000) = {:?}",
+        virtual_to_physical(VirtAddr::new(0x2000_0000))
+    );
+
+    // VGA buffer is reached through the physical memory offset
+    serial_println!(
+        "virtual_to_physical(vga) = {:?}",
+        virtual_to_physical(phys_to_virt(PhysAddr::new(0xb8000)))
+    );
+
+    // Kernel code is read only
+    serial_println!("WRITE .text");
+    let address = kernel_main as *const u8 as *mut Volatile<u8>;
+    unsafe {
+        (*address).write(0);
+    }
+    serial_println!("AFTER WRITE .text");
+
+    qemu::exit_success();
+    loop {}
+}
+
+extern "C" fn page_fault_handler(arg: &mut IsrArg) {
+    // error_code = present + write
+    serial_println!("PAGE_FAULT: error_code = {}", { arg.error_code });
+    qemu::exit_success();
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![feature(asm)]
+#![feature(panic_info_message)]
+
+use core::fmt::Write;
+use os::idt::IDT;
+use os::qemu;
+use os::serial_println;
+
+// Unhandled exception is expected to end up here (without source location to keep output stable)
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    if let Some(message) = info.message() {
+        os::serial_print!("panic: ");
+        FirstLine(false).write_fmt(*message).unwrap();
+        serial_println!();
+    }
+    qemu::exit_success();
+    loop {}
+}
+
+// Drop the rest of report since register values vary by build
+struct FirstLine(bool);
+
+impl Write for FirstLine {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if !self.0 {
+            match s.find('\n') {
+                Some(i) => {
+                    os::serial_print!("{}", &s[..i]);
+                    self.0 = true;
+                }
+                None => os::serial_print!("{}", s),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main() -> ! {
+    IDT.lock().set_default_handlers();
+
+    // Reserved vectors get the default handler as well
+    unsafe {
+        asm!("int 22");
+    }
+    serial_println!("AFTER int 22");
+
+    loop {}
+}
@@ -0,0 +1,99 @@
+#![no_std]
+#![feature(naked_functions)]
+#![feature(asm)]
+#![feature(alloc_error_handler)]
+
+use os::asm::read_cr2;
+use os::idt::{IdtIndex, IsrArg, IDT};
+use os::make_isr;
+use os::memory::heap_allocator::HEAP_PAGE_FLAGS;
+use os::memory::paging::map_page_range;
+use os::memory::slab_allocator::MutexSlabHeapAllocator;
+use os::memory::{BitmapFrameAllocator, Page, VirtAddr};
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+use os::util::Mutex;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("{}", info);
+    qemu::exit_fail();
+    loop {}
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    // Set page fault handler
+    IDT.lock().set_handler(
+        IdtIndex::PageFault,
+        make_isr!(page_fault_handler, has_error_code),
+    );
+
+    let memory_regions = boot_info.memory_regions();
+    // Initialize heap memory (slab pages are mapped on demand)
+    *FRAME_ALLOCATOR.lock() = Some(BitmapFrameAllocator::new(
+        memory_regions.usable(),
+        memory_regions.occupied(),
+    ));
+    map_page_range(
+        Page::range(
+            Page::containing_address(VirtAddr::new(HEAP_START as u64)),
+            Page::containing_address(VirtAddr::new(HEAP_END as u64)),
+        ),
+        HEAP_PAGE_FLAGS,
+        &mut *FRAME_ALLOCATOR.lock(),
+    )
+    .unwrap();
+    HEAP_ALLOCATOR.init(&FRAME_ALLOCATOR);
+
+    // Small objects from slab
+    let xs: Vec<Box<u64>> = (0..100).map(Box::new).collect();
+    serial_println!("sum = {}", xs.iter().map(|x| **x).sum::<u64>());
+    serial_println!("{:?}", HEAP_ALLOCATOR.slab_stats()[0]);
+    drop(xs);
+    serial_println!("{:?}", HEAP_ALLOCATOR.slab_stats()[0]);
+
+    // Large object from heap
+    let ys: Vec<u8> = (0..10000).map(|i| (i % 2) as u8).collect();
+    serial_println!("sum = {}", ys.iter().map(|&y| y as u64).sum::<u64>());
+
+    qemu::exit_success();
+    loop {}
+}
+
+extern "C" fn page_fault_handler(arg: &mut IsrArg) {
+    serial_println!(
+        "PAGE_FAULT: error_code = {}, cr2 = 0x{:08x}",
+        { arg.error_code },
+        read_cr2()
+    );
+    qemu::exit_success();
+}
+
+//
+// Rust heap allocator interface
+//
+
+const SLAB_START: usize = 0x4800_0000;
+const SLAB_END: usize = SLAB_START + (1 << 20); // 1MB
+
+const HEAP_START: usize = 0x4000_0000; // right after the boot time identity map
+const HEAP_END: usize = HEAP_START + (1 << 14); // 16KB = 4 pages
+
+#[global_allocator]
+static HEAP_ALLOCATOR: MutexSlabHeapAllocator =
+    MutexSlabHeapAllocator::new(SLAB_START, SLAB_END, HEAP_START, HEAP_END);
+
+#[alloc_error_handler]
+pub fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
+    serial_println!("alloc_error: {:?}", layout);
+    qemu::exit_success();
+    loop {}
+}
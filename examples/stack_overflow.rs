@@ -0,0 +1,86 @@
+#![no_std]
+#![feature(naked_functions)]
+#![feature(asm)]
+#![feature(panic_info_message)]
+
+use os::gdt;
+use os::idt::IDT;
+use os::memory::stack_allocator::{set_double_fault_handler, KERNEL_STACKS};
+use os::memory::BitmapFrameAllocator;
+use os::multiboot2::BootInfo;
+use os::qemu;
+use os::serial_println;
+use os::util::{address_cast, address_cast_mut, Volatile};
+
+// Overflow is expected to end up here (without source location to keep output stable)
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    if let Some(message) = info.message() {
+        serial_println!("panic: {}", message);
+    }
+    qemu::exit_success();
+    loop {}
+}
+
+// Each frame keeps a buffer alive across the recursive call, so it can't be optimized away
+#[inline(never)]
+fn recurse(depth: u64) -> u64 {
+    let mut buffer = [0u64; 16];
+    unsafe { core::ptr::write_volatile(&mut buffer[0], depth) };
+    if depth == u64::MAX {
+        return depth;
+    }
+    recurse(depth + 1) + unsafe { core::ptr::read_volatile(&buffer[0]) }
+}
+
+extern "C" fn overflow() -> ! {
+    recurse(0);
+    unreachable!();
+}
+
+#[no_mangle]
+pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    gdt::init();
+    IDT.lock().set_default_handlers();
+    set_double_fault_handler(&mut IDT.lock());
+
+    let memory_regions = boot_info.memory_regions();
+    let mut allocator =
+        BitmapFrameAllocator::new(memory_regions.usable(), memory_regions.occupied());
+    // NOTE: double fault handler locks KERNEL_STACKS, so it's not held across overflow
+    let stack1 = {
+        let mut stacks = KERNEL_STACKS.lock();
+        let stack0 = stacks.allocate(4, &mut allocator).unwrap();
+        let stack1 = stacks.allocate(4, &mut allocator).unwrap();
+        // Freed slot is reused
+        stacks.deallocate(stack0, &mut allocator);
+        let stack0 = stacks.allocate(8, &mut allocator).unwrap();
+        serial_println!("stack 0 reused = {}", stack0.index() == 0);
+        stack1
+    };
+
+    // Whole stack is usable
+    let top = (stack1.top() - 8).as_u64() as usize;
+    let bottom = stack1.bottom().as_u64() as usize;
+    unsafe {
+        address_cast_mut::<Volatile<u64>>(top).write(1);
+        address_cast_mut::<Volatile<u64>>(bottom).write(2);
+    }
+    serial_println!("stack 1 usable = {}", unsafe {
+        address_cast::<Volatile<u64>>(top).read() + address_cast::<Volatile<u64>>(bottom).read()
+            == 3
+    });
+
+    // Recurse on stack 1 until a call hits the guard page. The page fault cannot push its
+    // frame to the overflowing stack either, so the CPU raises a double fault on the IST stack.
+    serial_println!("OVERFLOW stack 1");
+    unsafe {
+        asm!(
+            "mov rsp, {0}",
+            "call {1}",
+            in(reg) stack1.top().as_u64(),
+            sym overflow,
+            options(noreturn)
+        );
+    }
+}
@@ -52,6 +52,44 @@ pub fn lidt<T>(ptr: *const T) {
     }
 }
 
+// lgdt (same pointer layout as lidt)
+pub fn lgdt<T>(ptr: *const T) {
+    let ptr = ptr as *const u128;
+    unsafe {
+        llvm_asm!("lgdt $0" : : "m"(*ptr));
+    }
+}
+
+// ltr
+pub fn ltr(selector: u16) {
+    unsafe {
+        llvm_asm!("ltr $0" : : "r"(selector) : : "volatile");
+    }
+}
+
+// Segment registers (`mov` cannot write cs, so it's reloaded by far return)
+pub fn set_cs(selector: u16) {
+    unsafe {
+        llvm_asm!("
+            pushq $0
+            leaq 1f(%rip), %rax
+            pushq %rax
+            lretq
+        1:
+        " : : "r"(selector as u64) : "rax", "memory" : "volatile");
+    }
+}
+
+pub fn set_data_segments(selector: u16) {
+    unsafe {
+        llvm_asm!("
+            mov $0, %ds
+            mov $0, %es
+            mov $0, %ss
+        " : : "r"(selector) : "memory" : "volatile");
+    }
+}
+
 // int3
 pub fn int3() {
     unsafe {
@@ -62,10 +100,28 @@ pub fn int3() {
 // sti
 pub fn sti() {
     unsafe {
-        llvm_asm!("sti");
+        llvm_asm!("sti" : : : "memory" : "volatile");
     }
 }
 
+// cli
+pub fn cli() {
+    unsafe {
+        llvm_asm!("cli" : : : "memory" : "volatile");
+    }
+}
+
+// rflags
+pub const RFLAGS_IF: u64 = 1 << 9; // interrupt enable
+
+pub fn read_rflags() -> u64 {
+    let value: u64;
+    unsafe {
+        llvm_asm!("pushfq; popq $0" : "=r"(value) : : "memory" : "volatile");
+    }
+    value
+}
+
 pub fn hlt() {
     unsafe {
         llvm_asm!("hlt");
@@ -81,7 +137,73 @@ pub fn read_cr2() -> u64 {
     value
 }
 
-// flush tlb
+// cpuid
+pub use core::arch::x86_64::CpuidResult;
+
+pub fn cpuid(leaf: u32) -> CpuidResult {
+    unsafe { core::arch::x86_64::__cpuid(leaf) }
+}
+
+// msr (cf. https://wiki.osdev.org/Model_Specific_Registers)
+pub const EFER: u32 = 0xC0000080;
+pub const EFER_NXE: u64 = 1 << 11;
+pub const IA32_PAT: u32 = 0x277;
+
+pub fn rdmsr(msr: u32) -> u64 {
+    let (hi, lo): (u32, u32);
+    unsafe {
+        llvm_asm!("rdmsr" : "={edx}"(hi), "={eax}"(lo) : "{ecx}"(msr) : : "volatile");
+    }
+    ((hi as u64) << 32) | (lo as u64)
+}
+
+pub fn wrmsr(msr: u32, value: u64) {
+    let (hi, lo) = ((value >> 32) as u32, value as u32);
+    unsafe {
+        llvm_asm!("wrmsr" : : "{ecx}"(msr), "{edx}"(hi), "{eax}"(lo) : : "volatile");
+    }
+}
+
+// cr0 (cf. https://en.wikipedia.org/wiki/Control_register#CR0)
+pub const CR0_WRITE_PROTECT: u64 = 1 << 16;
+
+pub fn read_cr0() -> u64 {
+    let value: u64;
+    unsafe {
+        llvm_asm!("mov %cr0, $0" : "=r"(value));
+    }
+    value
+}
+
+pub fn write_cr0(value: u64) {
+    unsafe {
+        llvm_asm!("mov $0, %cr0" : : "r"(value) : "memory" : "volatile");
+    }
+}
+
+// cr3
+pub fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe {
+        llvm_asm!("mov %cr3, $0" : "=r"(value));
+    }
+    value
+}
+
+pub fn write_cr3(value: u64) {
+    unsafe {
+        llvm_asm!("mov $0, %cr3" : : "r"(value) : "memory" : "volatile");
+    }
+}
+
+// flush tlb entry of the page containing `addr`
+pub fn invlpg(addr: u64) {
+    unsafe {
+        llvm_asm!("invlpg ($0)" : : "r"(addr) : "memory" : "volatile");
+    }
+}
+
+// flush whole tlb (except global pages)
 pub fn flush_tlb() {
     unsafe {
         llvm_asm!("mov %cr3, %rax");
@@ -30,6 +30,18 @@ dw 0
 dd 8
 .end:
 
+; Kernel is linked at the higher half (cf. linker.ld) and 32 bit code runs at physical address
+KERNEL_OFFSET equ 0xFFFFFFFF80000000
+KERNEL_P4_INDEX equ 511
+KERNEL_P3_INDEX equ 510
+RECURSIVE_P4_INDEX equ 510
+
+; First 4GB of physical memory is mapped here (cf. PHYSICAL_MEMORY_OFFSET in memory.rs)
+PHYSICAL_MEMORY_OFFSET equ 0xFFFF800000000000
+PHYSICAL_MEMORY_P4_INDEX equ 256
+PHYSICAL_MEMORY_GB equ 4
+
+
 ; Stack
 section .kernel_stack
 stack_bottom:
@@ -45,14 +57,18 @@ p4_table:
   resb 1 << 12
 p3_table:
   resb 1 << 12
+p3_kernel_table:
+  resb 1 << 12
 p2_table:
   resb 1 << 12
-p1_tables: ; p1_table x 2^9
-  resb 1 << 21
+p3_physical_table:
+  resb 1 << 12
+p2_physical_tables:
+  resb PHYSICAL_MEMORY_GB << 12
 page_tables_end:
 
-; GDT
-section .rodata
+; GDT (lgdt in 32 bit mode only takes 32 bit base, so it lives with boot code)
+section .boot progbits alloc exec nowrite
 gdt64:
   dq 0
 .code: equ $ - gdt64
@@ -63,12 +79,12 @@ gdt64:
 
 
 ; Kernel entry point
-section .text
+section .boot
 bits 32
 global start
 start:
   ; Initialize stack pointer
-  mov esp, stack_top
+  mov esp, stack_top - KERNEL_OFFSET
 
   ; Move multiboot info to edi, which becomes 1st argument for `kernel_main`
   mov edi, ebx
@@ -146,56 +162,80 @@ check_long_mode:
   call print_error
 
 
-; Setup 1GB identity map with recursive entry
-; 1 x 1 x 2^9 x 2^9 = 2^18 pages = 2^18 x 2^12 bytes
+; Setup 1GB identity map, the same 1GB at KERNEL_OFFSET, 4GB at PHYSICAL_MEMORY_OFFSET,
+; and recursive entry
+; 1 x 1 x 2^9 huge pages = 2^9 x 2^21 bytes (4 x 2^9 huge pages for PHYSICAL_MEMORY_OFFSET)
 setup_page_tables:
   ; Initialize with zero
   mov eax, 0
-  mov ecx, page_tables_start
+  mov ecx, page_tables_start - KERNEL_OFFSET
 .zero_loop
   mov [ecx], eax
   add ecx, 4
-  cmp ecx, page_tables_end
+  cmp ecx, page_tables_end - KERNEL_OFFSET
   jne .zero_loop
 
   ; Chain first entry p4 -> p3 -> p2
-  mov eax, p2_table
+  mov eax, p2_table - KERNEL_OFFSET
   or eax, (1 << 0 | 1 << 1) ; flag (writable + present)
-  mov [p3_table], eax
+  mov [p3_table - KERNEL_OFFSET], eax
+
+  mov eax, p3_table - KERNEL_OFFSET
+  or eax, (1 << 0 | 1 << 1)
+  mov [p4_table - KERNEL_OFFSET], eax
+
+  ; Chain higher half entry p4 -> p3 -> (same) p2
+  mov eax, p2_table - KERNEL_OFFSET
+  or eax, (1 << 0 | 1 << 1)
+  mov [p3_kernel_table - KERNEL_OFFSET + 8 * KERNEL_P3_INDEX], eax
 
-  mov eax, p3_table
+  mov eax, p3_kernel_table - KERNEL_OFFSET
   or eax, (1 << 0 | 1 << 1)
-  mov [p4_table], eax
+  mov [p4_table - KERNEL_OFFSET + 8 * KERNEL_P4_INDEX], eax
+
+  ; Chain physical memory entry p4 -> p3 -> p2 (one p2 per 1GB)
+  mov eax, p3_physical_table - KERNEL_OFFSET
+  or eax, (1 << 0 | 1 << 1)
+  mov [p4_table - KERNEL_OFFSET + 8 * PHYSICAL_MEMORY_P4_INDEX], eax
+
+  mov ecx, 0
+.p3_physical_loop:
+  mov eax, ecx
+  shl eax, 12
+  add eax, p2_physical_tables - KERNEL_OFFSET
+  or eax, (1 << 0 | 1 << 1)
+  mov [p3_physical_table - KERNEL_OFFSET + 8 * ecx], eax
+  add ecx, 1
+  cmp ecx, PHYSICAL_MEMORY_GB
+  jne .p3_physical_loop
 
   ; Recursive entry
-  mov eax, p4_table
+  mov eax, p4_table - KERNEL_OFFSET
   or eax, (1 << 0 | 1 << 1)
-  mov [p4_table + 8 * ((1 << 9) - 1)], eax
+  mov [p4_table - KERNEL_OFFSET + 8 * RECURSIVE_P4_INDEX], eax
 
-  ; loop p2 entries
+  ; loop p2 entries (2MB huge page each)
   mov ecx, 0
 .p2_loop:
-  ; eax = (p1_tables + 2^12 * ecx) | 0b11
-  mov eax, 1 << 12
+  ; eax = 2^21 * ecx | 0b10000011
+  mov eax, 1 << 21
   mul ecx
-  add eax, p1_tables
-  or eax, (1 << 0 | 1 << 1)
-  mov [p2_table + 8 * ecx], eax
+  or eax, (1 << 0 | 1 << 1 | 1 << 7) ; flag (huge + writable + present)
+  mov [p2_table - KERNEL_OFFSET + 8 * ecx], eax
   add ecx, 1
   cmp ecx, (1 << 9)
   jne .p2_loop
 
-  ; loop p1 entries
+  ; loop p2 entries of physical memory (contiguous tables, so addresses stay below 4GB)
   mov ecx, 0
-.p1_loop:
-  ; eax = 2^12 * ecx | 0b11
-  mov eax, 1 << 12
+.p2_physical_loop:
+  mov eax, 1 << 21
   mul ecx
-  or eax, (1 << 0 | 1 << 1)
-  mov [p1_tables + 8 * ecx], eax
+  or eax, (1 << 0 | 1 << 1 | 1 << 7)
+  mov [p2_physical_tables - KERNEL_OFFSET + 8 * ecx], eax
   add ecx, 1
-  cmp ecx, (1 << 18)
-  jne .p1_loop
+  cmp ecx, PHYSICAL_MEMORY_GB << 9
+  jne .p2_physical_loop
 
   ret
 
@@ -203,7 +243,7 @@ setup_page_tables:
 ; Enable paging (cf. https://en.wikipedia.org/wiki/Control_register)
 enable_paging:
   ; cr3 = p4
-  mov eax, p4_table
+  mov eax, p4_table - KERNEL_OFFSET
   mov cr3, eax
 
   ; PAE bit on cr4
@@ -211,10 +251,17 @@ enable_paging:
   or eax, 1 << 5
   mov cr4, eax
 
-  ; LME bit on EFER
+  ; LME bit on EFER (and NXE bit if "no execute" is supported)
+  mov eax, 0x80000001
+  cpuid
+  mov esi, edx
   mov ecx, 0xC0000080
   rdmsr
   or eax, 1 << 8
+  test esi, 1 << 20
+  jz .no_execute_unsupported
+  or eax, 1 << 11
+.no_execute_unsupported:
   wrmsr
 
   ; PG bit on cr0
@@ -224,8 +271,8 @@ enable_paging:
   ret
 
 
-; Long mode entry point
-section .text
+; Long mode entry point (still running at physical address)
+section .boot
 bits 64
 global start_long_mode
 start_long_mode:
@@ -235,8 +282,23 @@ start_long_mode:
   mov fs, ax
   mov gs, ax
 
+  ; Jump to higher half (too far for relative jump)
+  mov rax, qword start_higher_half
+  jmp rax
+
+
+section .text
+bits 64
+start_higher_half:
+  ; Switch to higher half address of the same stack
+  mov rsp, stack_top
+
   ; Call Rust entrypoint
-  ; (1st argument is a pointer to multiboot information, see "mov edi, ebx" in "start")
+  ; (1st argument is a pointer to multiboot information, see "mov edi, ebx" in "start",
+  ;  which is translated to the physical memory mapping)
+  mov edi, edi
+  mov rax, PHYSICAL_MEMORY_OFFSET
+  add rdi, rax
   extern kernel_main
   call kernel_main
 
@@ -0,0 +1,178 @@
+use crate::asm::{lgdt, ltr, set_cs, set_data_segments};
+use crate::util::Mutex;
+
+// cf.
+// - https://wiki.osdev.org/Global_Descriptor_Table
+// - https://wiki.osdev.org/Task_State_Segment
+
+// User segments are ordered as `sysret` expects (data right before code)
+pub const KERNEL_CODE_SELECTOR: u16 = 1 << 3;
+pub const KERNEL_DATA_SELECTOR: u16 = 2 << 3;
+pub const USER_DATA_SELECTOR: u16 = 3 << 3 | 3;
+pub const USER_CODE_SELECTOR: u16 = 4 << 3 | 3;
+pub const TSS_SELECTOR: u16 = 5 << 3;
+
+// Interrupt stack table index is 1-based (0 means the current stack is used)
+pub const NUM_IST_STACKS: usize = 7;
+pub const IST_STACK_SIZE: usize = 4096 * 4;
+pub const DOUBLE_FAULT_IST_INDEX: u8 = 1;
+
+#[repr(C)]
+#[repr(packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct TaskStateSegment {
+    reserved1: u32,
+    pub privilege_stack_table: [u64; 3], // rsp loaded when entering ring 0-2
+    reserved2: u64,
+    pub interrupt_stack_table: [u64; NUM_IST_STACKS],
+    reserved3: u64,
+    reserved4: u16,
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    pub const fn new() -> Self {
+        Self {
+            reserved1: 0,
+            privilege_stack_table: [0; 3],
+            reserved2: 0,
+            interrupt_stack_table: [0; NUM_IST_STACKS],
+            reserved3: 0,
+            reserved4: 0,
+            // No I/O permission bitmap
+            iomap_base: core::mem::size_of::<Self>() as u16,
+        }
+    }
+}
+
+// Segment descriptor flags (base and limit are ignored in long mode except for TSS)
+const ACCESSED: u64 = 1 << 40;
+const WRITABLE: u64 = 1 << 41;
+const EXECUTABLE: u64 = 1 << 43;
+const USER_SEGMENT: u64 = 1 << 44;
+const DPL_RING_3: u64 = 3 << 45;
+const PRESENT: u64 = 1 << 47;
+const LIMIT_MAX: u64 = 0xf << 48 | 0xffff;
+const LONG_MODE: u64 = 1 << 53;
+const SIZE_32: u64 = 1 << 54;
+const GRANULARITY: u64 = 1 << 55;
+const AVAILABLE_TSS: u64 = 0x9 << 40;
+
+const COMMON: u64 = USER_SEGMENT | PRESENT | WRITABLE | ACCESSED | LIMIT_MAX | GRANULARITY;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Descriptor {
+    User(u64),
+    System(u64, u64), // takes two entries
+}
+
+impl Descriptor {
+    pub const KERNEL_CODE: Self = Self::User(COMMON | EXECUTABLE | LONG_MODE);
+    pub const KERNEL_DATA: Self = Self::User(COMMON | SIZE_32);
+    pub const USER_CODE: Self = Self::User(COMMON | EXECUTABLE | LONG_MODE | DPL_RING_3);
+    pub const USER_DATA: Self = Self::User(COMMON | SIZE_32 | DPL_RING_3);
+
+    // TSS stays at the same address since it's static
+    pub fn tss(tss: &'static Mutex<TaskStateSegment>) -> Self {
+        let base = &*tss.lock() as *const _ as u64;
+        let limit = (core::mem::size_of::<TaskStateSegment>() - 1) as u64;
+        let low =
+            PRESENT | AVAILABLE_TSS | limit | (base & 0xff_ffff) << 16 | (base >> 24 & 0xff) << 56;
+        Self::System(low, base >> 32)
+    }
+}
+
+const GDT_SIZE: usize = 8;
+
+#[repr(C)]
+#[repr(packed)]
+#[derive(Debug, Copy, Clone, Default)]
+struct GdtInfo {
+    size: u16,
+    offset: u64,
+}
+
+pub struct Gdt {
+    info: GdtInfo,
+    entries: [u64; GDT_SIZE],
+    len: usize,
+}
+
+impl Gdt {
+    pub const fn new() -> Self {
+        Self {
+            info: GdtInfo { size: 0, offset: 0 },
+            entries: [0; GDT_SIZE], // first one is null descriptor
+            len: 1,
+        }
+    }
+
+    // Returns selector of the added descriptor
+    pub fn add(&mut self, descriptor: Descriptor) -> u16 {
+        let index = self.len;
+        let (low, rpl) = match descriptor {
+            Descriptor::User(low) => (low, (low & DPL_RING_3) >> 45),
+            Descriptor::System(low, high) => {
+                self.push(low);
+                self.push(high);
+                return (index << 3) as u16;
+            }
+        };
+        self.push(low);
+        (index << 3) as u16 | rpl as u16
+    }
+
+    fn push(&mut self, entry: u64) {
+        assert!(self.len < GDT_SIZE, "GDT is full");
+        self.entries[self.len] = entry;
+        self.len += 1;
+    }
+
+    // Has to stay at the same address while loaded
+    pub fn load(&mut self) {
+        self.info.size = (self.len * 8 - 1) as u16;
+        self.info.offset = &self.entries as *const _ as u64;
+        lgdt(&self.info as *const _);
+    }
+}
+
+// Stacks used for exceptions which must not run on the current stack (e.g. double fault)
+#[repr(align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut IST_STACKS: [IstStack; NUM_IST_STACKS] = [
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+];
+
+pub static TSS: Mutex<TaskStateSegment> = Mutex::new(TaskStateSegment::new());
+
+pub static GDT: Mutex<Gdt> = Mutex::new(Gdt::new());
+
+// Replace the GDT from boot.asm with kernel/user segments and TSS whose interrupt stack table
+// points to `IST_STACKS`, then reload segment registers and task register
+pub fn init() {
+    let mut stacks = [0; NUM_IST_STACKS];
+    for (i, stack) in unsafe { IST_STACKS.iter().enumerate() } {
+        stacks[i] = stack.0.as_ptr() as u64 + IST_STACK_SIZE as u64;
+    }
+    TSS.lock().interrupt_stack_table = stacks; // no reference into packed struct
+
+    let mut gdt = GDT.lock();
+    *gdt = Gdt::new();
+    assert_eq!(gdt.add(Descriptor::KERNEL_CODE), KERNEL_CODE_SELECTOR);
+    assert_eq!(gdt.add(Descriptor::KERNEL_DATA), KERNEL_DATA_SELECTOR);
+    assert_eq!(gdt.add(Descriptor::USER_DATA), USER_DATA_SELECTOR);
+    assert_eq!(gdt.add(Descriptor::USER_CODE), USER_CODE_SELECTOR);
+    assert_eq!(gdt.add(Descriptor::tss(&TSS)), TSS_SELECTOR);
+    gdt.load();
+
+    set_cs(KERNEL_CODE_SELECTOR);
+    set_data_segments(KERNEL_DATA_SELECTOR);
+    ltr(TSS_SELECTOR);
+}
@@ -1,19 +1,112 @@
-use crate::asm::lidt;
+use crate::asm::{lidt, read_cr2};
+use crate::gdt::{KERNEL_CODE_SELECTOR, NUM_IST_STACKS};
 use crate::lazy_static;
+use crate::make_isr;
+use crate::memory::vma::{PAGE_FAULT_INSTRUCTION_FETCH, PAGE_FAULT_PRESENT};
+use crate::memory::vma::{PAGE_FAULT_RESERVED_WRITE, PAGE_FAULT_USER, PAGE_FAULT_WRITE};
+use crate::memory::VirtAddr;
 use crate::util::Mutex;
+use core::fmt;
 
 // cf. https://wiki.osdev.org/IDT
 
 const IDT_SIZE: usize = 256;
 
-#[derive(Debug, Copy, Clone)]
-#[allow(dead_code)]
+// Architectural exceptions (vectors 15, 22-27 and 31 are reserved)
+// cf. https://wiki.osdev.org/Exceptions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum IdtIndex {
     DivideByZero = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
     Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
     DoubleFault = 8,
+    CoprocessorSegmentOverrun = 9,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
     GeneralProtectionFault = 13,
     PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    ControlProtection = 21,
+    HypervisorInjection = 28,
+    VmmCommunication = 29,
+    Security = 30,
+}
+
+pub const NUM_EXCEPTIONS: usize = 32;
+
+const EXCEPTIONS: [IdtIndex; 24] = [
+    IdtIndex::DivideByZero,
+    IdtIndex::Debug,
+    IdtIndex::NonMaskableInterrupt,
+    IdtIndex::Breakpoint,
+    IdtIndex::Overflow,
+    IdtIndex::BoundRangeExceeded,
+    IdtIndex::InvalidOpcode,
+    IdtIndex::DeviceNotAvailable,
+    IdtIndex::DoubleFault,
+    IdtIndex::CoprocessorSegmentOverrun,
+    IdtIndex::InvalidTss,
+    IdtIndex::SegmentNotPresent,
+    IdtIndex::StackSegmentFault,
+    IdtIndex::GeneralProtectionFault,
+    IdtIndex::PageFault,
+    IdtIndex::X87FloatingPoint,
+    IdtIndex::AlignmentCheck,
+    IdtIndex::MachineCheck,
+    IdtIndex::SimdFloatingPoint,
+    IdtIndex::Virtualization,
+    IdtIndex::ControlProtection,
+    IdtIndex::HypervisorInjection,
+    IdtIndex::VmmCommunication,
+    IdtIndex::Security,
+];
+
+impl IdtIndex {
+    // None for reserved vectors and interrupts
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        EXCEPTIONS
+            .iter()
+            .copied()
+            .find(|&index| index as u8 == vector)
+    }
+
+    // Whether CPU pushes error code (`make_isr!` needs `has_error_code` for these)
+    pub fn has_error_code(self) -> bool {
+        matches!(
+            self,
+            IdtIndex::DoubleFault
+                | IdtIndex::InvalidTss
+                | IdtIndex::SegmentNotPresent
+                | IdtIndex::StackSegmentFault
+                | IdtIndex::GeneralProtectionFault
+                | IdtIndex::PageFault
+                | IdtIndex::AlignmentCheck
+                | IdtIndex::ControlProtection
+                | IdtIndex::VmmCommunication
+                | IdtIndex::Security
+        )
+    }
+
+    // Error code is a segment selector index (cf. https://wiki.osdev.org/Exceptions#Selector_Error_Code)
+    fn has_selector_error_code(self) -> bool {
+        matches!(
+            self,
+            IdtIndex::InvalidTss
+                | IdtIndex::SegmentNotPresent
+                | IdtIndex::StackSegmentFault
+                | IdtIndex::GeneralProtectionFault
+        )
+    }
 }
 
 #[repr(C)]
@@ -41,30 +134,39 @@ pub struct IdtInfo {
 #[repr(packed)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct IsrArg {
-    // Saved registers
-    r15: u64,
-    r14: u64,
-    r13: u64,
-    r12: u64,
-    r11: u64,
-    r10: u64,
-    r9: u64,
-    r8: u64,
-    rbp: u64,
-    rdi: u64,
-    rsi: u64,
-    rbx: u64,
-    rdx: u64,
-    rcx: u64,
-    rax: u64,
-    rsp: u64,
+    // Saved registers (restored before `iretq`, so handler can modify them)
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+    isr_rsp: u64, // stack pointer within ISR (points to `error_code`)
     // Some interrupts pushes additional error information (e.g. page fault),
     // Otherwise "zero" is manually pushed
     pub error_code: u64,
-    // TODO: More useful stuff on the stack
+    // Pushed by CPU and used by `iretq` to return
+    // (e.g. rip is the faulting instruction for faults and the next one for traps like int3)
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 
 type IdtHandler = extern "C" fn();
+
+// Function called by the wrapper `make_isr!` generates
+pub type IsrFunction = extern "C" fn(&mut IsrArg);
 type IdtEntries = [IdtEntry; IDT_SIZE];
 
 pub struct Idt {
@@ -92,18 +194,70 @@ impl Idt {
     }
 
     pub fn set_handler(&mut self, index: IdtIndex, handler: IdtHandler) {
+        self.set_trap_handler(index as u8, handler);
+    }
+
+    // Also for reserved vectors, which have no `IdtIndex`
+    fn set_trap_handler(&mut self, vector: u8, handler: IdtHandler) {
         let ptr = handler as *const char as u64; // Tricky raw function pointer extraction
-        self.entries[index as usize] = IdtEntry {
+        self.entries[vector as usize] = IdtEntry {
             offset1: ptr as u16,
             offset2: (ptr >> 16) as u16,
             offset3: (ptr >> 32) as u32,
             type_attr: 0x8F, // = flag (present + trap gate)
-            selector: 8,     // = first segment (gdt64.code in boot.asm)
-            ist: 0,          // TODO: separate stack to prevent triple fault?
+            selector: KERNEL_CODE_SELECTOR,
+            ist: 0, // current stack (cf. `set_ist`)
             zero: 0,
         };
     }
 
+    // Switch to stack `ist` of TSS's interrupt stack table on `index` (e.g. double fault
+    // caused by stack overflow cannot push its frame on the overflowing stack)
+    pub fn set_ist(&mut self, index: IdtIndex, ist: u8) {
+        assert!(ist as usize <= NUM_IST_STACKS, "invalid IST index {}", ist);
+        self.entries[index as usize].ist = ist;
+    }
+
+    pub fn ist(&self, index: IdtIndex) -> u8 {
+        self.entries[index as usize].ist
+    }
+
+    // Every exception panics with decoded report unless other handler is set later
+    pub fn set_default_handlers(&mut self) {
+        macro_rules! set {
+            ($($index:ident),*; $($index_with_error_code:ident),*) => {
+                $(
+                    let handler = make_isr!(default_handler::<{ IdtIndex::$index as u8 }>);
+                    self.set_handler(IdtIndex::$index, handler);
+                )*
+                $(
+                    let handler = make_isr!(
+                        default_handler::<{ IdtIndex::$index_with_error_code as u8 }>,
+                        has_error_code
+                    );
+                    self.set_handler(IdtIndex::$index_with_error_code, handler);
+                )*
+            };
+        }
+        set!(
+            DivideByZero, Debug, NonMaskableInterrupt, Breakpoint, Overflow, BoundRangeExceeded,
+            InvalidOpcode, DeviceNotAvailable, CoprocessorSegmentOverrun, X87FloatingPoint,
+            MachineCheck, SimdFloatingPoint, Virtualization, HypervisorInjection;
+            DoubleFault, InvalidTss, SegmentNotPresent, StackSegmentFault, GeneralProtectionFault,
+            PageFault, AlignmentCheck, ControlProtection, VmmCommunication, Security
+        );
+
+        // CPU doesn't raise reserved vectors, but e.g. `int 15` still reaches them
+        macro_rules! set_reserved {
+            ($($vector:literal),*) => {
+                $(
+                    self.set_trap_handler($vector, make_isr!(default_handler::<$vector>));
+                )*
+            };
+        }
+        set_reserved!(15, 22, 23, 24, 25, 26, 27, 31);
+    }
+
     pub fn set_irq_handler(&mut self, index: u8, handler: IdtHandler) {
         let ptr = handler as *const char as u64;
         self.entries[index as usize] = IdtEntry {
@@ -111,7 +265,7 @@ impl Idt {
             offset2: (ptr >> 16) as u16,
             offset3: (ptr >> 32) as u32,
             type_attr: 0x8E, // = flag (present + interrupt gate)
-            selector: 8,     // = first segment (gdt64.code in boot.asm)
+            selector: KERNEL_CODE_SELECTOR,
             ist: 0,
             zero: 0,
         };
@@ -128,11 +282,12 @@ macro_rules! make_isr__intro {
             push r8; push r9; push r10; push r11; push r12; push r13; push r14; push r15
 
             # System V calling convention
-            # Make 1st argument a pointer to stack, which is all the data pushed so far (registers, index, error_code)
+            # Make 1st argument a pointer to stack, which is all the data pushed so far
+            # (registers, error_code and interrupt frame pushed by CPU)
             mov rdi, rsp
             "
         )
-    }
+    };
 }
 
 #[macro_export]
@@ -154,7 +309,8 @@ macro_rules! make_isr__outro {
 
 #[macro_export]
 macro_rules! make_isr {
-    ($function:ident) => {{
+    ($function:path) => {{
+        let _: $crate::idt::IsrFunction = $function; // Check signature (outside naked function)
         #[naked]
         #[allow(unsupported_naked_functions)] // multiple asm blocks in single naked function will be deprecated...
         extern "C" fn wrapper() {
@@ -168,7 +324,8 @@ macro_rules! make_isr {
         wrapper
     }};
 
-    ($function:ident, has_error_code) => {{
+    ($function:path, has_error_code) => {{
+        let _: $crate::idt::IsrFunction = $function;
         #[naked]
         #[allow(unsupported_naked_functions)]
         extern "C" fn wrapper() {
@@ -182,11 +339,92 @@ macro_rules! make_isr {
     }};
 }
 
+// Exception report with error code decoded (vector < `NUM_EXCEPTIONS`)
+pub struct ExceptionReport<'a> {
+    pub vector: u8,
+    pub arg: &'a IsrArg,
+}
+
+impl ExceptionReport<'_> {
+    fn fmt_error_code(&self, index: IdtIndex, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let error_code = self.arg.error_code;
+        write!(f, ", error_code = 0x{:x}", error_code)?;
+        if index.has_selector_error_code() && error_code != 0 {
+            const TABLES: [&str; 4] = ["GDT", "IDT", "LDT", "IDT"];
+            write!(
+                f,
+                " ({} index {}{})",
+                TABLES[((error_code >> 1) & 0b11) as usize],
+                (error_code >> 3) & 0x1fff,
+                if error_code & 1 != 0 {
+                    ", external"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        if index == IdtIndex::PageFault {
+            let cause = if error_code & PAGE_FAULT_PRESENT != 0 {
+                "protection violation"
+            } else {
+                "page not present"
+            };
+            let access = if error_code & PAGE_FAULT_INSTRUCTION_FETCH != 0 {
+                "instruction fetch"
+            } else if error_code & PAGE_FAULT_WRITE != 0 {
+                "write"
+            } else {
+                "read"
+            };
+            let address = VirtAddr::new_truncate(read_cr2());
+            write!(f, " ({} on {} at {:?}", cause, access, address)?;
+            if error_code & PAGE_FAULT_USER != 0 {
+                write!(f, " from user mode")?;
+            }
+            if error_code & PAGE_FAULT_RESERVED_WRITE != 0 {
+                write!(f, ", reserved bit set")?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match IdtIndex::from_vector(self.vector) {
+            Some(index) => {
+                write!(f, "{:?} (vector {})", index, self.vector)?;
+                if index.has_error_code() {
+                    self.fmt_error_code(index, f)?;
+                }
+            }
+            None => write!(f, "Reserved (vector {})", self.vector)?,
+        }
+        let arg = self.arg;
+        write!(
+            f,
+            "\n  rip = 0x{:x}, cs = 0x{:x}, rflags = 0x{:x}, rsp = 0x{:x}, ss = 0x{:x}",
+            { arg.rip },
+            { arg.cs },
+            { arg.rflags },
+            { arg.rsp },
+            { arg.ss }
+        )
+    }
+}
+
+extern "C" fn default_handler<const VECTOR: u8>(arg: &mut IsrArg) {
+    panic!(
+        "unhandled exception {}",
+        ExceptionReport {
+            vector: VECTOR,
+            arg
+        }
+    );
+}
+
 lazy_static! {
-    // TODO:
-    //   This doesn't work
-    //     { let mut idt = Idt::new(); idt.load(); Mutex::new(idt) }
-    //   since idt.offset() changes during lazy_static.
-    //   probably this is because of a bunch of undefined behaviours happening in my lazy static.
-    pub static ref IDT : Mutex<Idt> = Mutex::new(Idt::new());
+    // Loaded after it's placed in the static (CPU keeps its address)
+    pub static ref IDT : Mutex<Idt> = Mutex::new(Idt::new()) => |idt| idt.lock().load();
 }
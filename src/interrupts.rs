@@ -0,0 +1,155 @@
+use crate::idt::{ExceptionReport, Idt, IdtIndex, IsrArg, NUM_EXCEPTIONS};
+use crate::pic::{self, NUM_IRQS, PIC1_IDT_OFFSET};
+use crate::util::IrqSafeMutex;
+use core::ops::{Deref, DerefMut};
+
+// Dispatch layer on top of `Idt`:
+// every vector enters the same stub which calls a handler registered in `HANDLERS`,
+// so handlers are plain functions (or non-capturing closures) instead of `make_isr!` wrappers.
+
+const NUM_VECTORS: usize = 256;
+
+// Registers and interrupt frame (`arg`) of the interrupted code with its vector
+pub struct InterruptContext<'a> {
+    pub vector: u8,
+    pub arg: &'a mut IsrArg,
+}
+
+impl InterruptContext<'_> {
+    pub fn irq(&self) -> Option<u8> {
+        pic::irq_from_vector(self.vector)
+    }
+}
+
+impl Deref for InterruptContext<'_> {
+    type Target = IsrArg;
+
+    fn deref(&self) -> &IsrArg {
+        self.arg
+    }
+}
+
+impl DerefMut for InterruptContext<'_> {
+    fn deref_mut(&mut self) -> &mut IsrArg {
+        self.arg
+    }
+}
+
+pub type InterruptHandler = fn(&mut InterruptContext);
+
+static HANDLERS: IrqSafeMutex<[Option<InterruptHandler>; NUM_VECTORS]> =
+    IrqSafeMutex::new([None; NUM_VECTORS]);
+
+// Returns the previous handler
+pub fn register(vector: u8, handler: InterruptHandler) -> Option<InterruptHandler> {
+    HANDLERS.lock()[vector as usize].replace(handler)
+}
+
+pub fn unregister(vector: u8) -> Option<InterruptHandler> {
+    HANDLERS.lock()[vector as usize].take()
+}
+
+// EOI is sent after `handler` returns (spurious IRQ 7/15 doesn't reach `handler`)
+pub fn register_irq(irq: u8, handler: InterruptHandler) -> Option<InterruptHandler> {
+    assert!(irq < NUM_IRQS, "invalid IRQ {}", irq);
+    register(PIC1_IDT_OFFSET + irq, handler)
+}
+
+pub fn unregister_irq(irq: u8) -> Option<InterruptHandler> {
+    assert!(irq < NUM_IRQS, "invalid IRQ {}", irq);
+    unregister(PIC1_IDT_OFFSET + irq)
+}
+
+// Point all vectors of `idt` to the dispatch stubs.
+// They are interrupt gates, so handlers run with interrupts disabled.
+// Stacks set by `Idt::set_ist` (e.g. for double fault) are kept.
+pub fn init(idt: &mut Idt) {
+    for (vector, &stub) in STUBS.iter().flatten().enumerate() {
+        let vector = vector as u8;
+        let ist = IdtIndex::from_vector(vector).map(|index| (index, idt.ist(index)));
+        idt.set_irq_handler(vector, stub);
+        if let Some((index, ist)) = ist {
+            idt.set_ist(index, ist);
+        }
+    }
+}
+
+extern "C" fn dispatch(arg: &mut IsrArg, vector: u64) {
+    let vector = vector as u8;
+    if let Some(irq) = pic::irq_from_vector(vector).filter(|&irq| pic::is_spurious(irq)) {
+        pic::spurious_eoi(irq);
+        return;
+    }
+    let handler = HANDLERS.lock()[vector as usize];
+    let mut context = InterruptContext { vector, arg };
+    match handler {
+        Some(handler) => handler(&mut context),
+        None => unhandled(&context),
+    }
+    if let Some(irq) = context.irq() {
+        pic::eoi(irq);
+    }
+}
+
+// Exceptions panic as `Idt::set_default_handlers` does, IRQs are ignored
+fn unhandled(context: &InterruptContext) {
+    let vector = context.vector;
+    if (vector as usize) < NUM_EXCEPTIONS {
+        panic!(
+            "unhandled exception {}",
+            ExceptionReport {
+                vector,
+                arg: context.arg
+            }
+        );
+    } else if context.irq().is_none() {
+        panic!("unhandled interrupt (vector {})", vector);
+    }
+}
+
+// Common part of stubs: rdi = IsrArg and rsi = vector are set by `stub`
+#[naked]
+#[allow(unsupported_naked_functions)]
+extern "C" fn common_stub() {
+    unsafe {
+        asm!("call {0}", sym dispatch);
+        crate::make_isr__outro!();
+    }
+}
+
+// Same stack layout as `make_isr!` (error code is pushed when CPU doesn't, cf. `IdtIndex::has_error_code`)
+#[naked]
+#[allow(unsupported_naked_functions)]
+extern "C" fn stub<const VECTOR: u8>() {
+    unsafe {
+        asm!(
+            ".if {0} == 8 || ({0} >= 10 && {0} <= 14) || {0} == 17 || {0} == 21 || {0} == 29 || {0} == 30",
+            ".else",
+            "push 0",
+            ".endif",
+            const VECTOR
+        );
+        crate::make_isr__intro!();
+        asm!("mov esi, {0}", "jmp {1}", const VECTOR, sym common_stub);
+    }
+}
+
+type StubFunction = extern "C" fn();
+
+macro_rules! stubs {
+    ($($high:literal)*) => {
+        [$([
+            stub::<{ $high * 16 }>, stub::<{ $high * 16 + 1 }>,
+            stub::<{ $high * 16 + 2 }>, stub::<{ $high * 16 + 3 }>,
+            stub::<{ $high * 16 + 4 }>, stub::<{ $high * 16 + 5 }>,
+            stub::<{ $high * 16 + 6 }>, stub::<{ $high * 16 + 7 }>,
+            stub::<{ $high * 16 + 8 }>, stub::<{ $high * 16 + 9 }>,
+            stub::<{ $high * 16 + 10 }>, stub::<{ $high * 16 + 11 }>,
+            stub::<{ $high * 16 + 12 }>, stub::<{ $high * 16 + 13 }>,
+            stub::<{ $high * 16 + 14 }>, stub::<{ $high * 16 + 15 }>,
+        ]),*]
+    };
+}
+
+static STUBS: [[StubFunction; 16]; NUM_VECTORS / 16] =
+    stubs!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
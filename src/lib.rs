@@ -1,10 +1,13 @@
 #![no_std]
 #![feature(asm)]
+#![feature(const_fn_trait_bound)]
 #![feature(llvm_asm)]
 #![feature(naked_functions)]
 
 pub mod asm;
+pub mod gdt;
 pub mod idt;
+pub mod interrupts;
 pub mod keyboard;
 pub mod memory;
 pub mod multiboot;
@@ -1,262 +1,3453 @@
+use crate::util::{address_cast_mut, Mutex};
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
 pub const PAGE_SIZE: u64 = 1 << 12; // 4096 = 4KB
 
-// For now, just type aliases
-pub type Frame = u64;
-pub type Page = u64;
-pub type PhysicalAddress = u64;
-pub type VirtualAddress = u64;
+//
+// Address, frame and page types
+//
+
+// Physical address (at most 52 bits)
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct PhysAddr(u64);
+
+const PHYS_ADDR_BITS: u64 = 52;
+
+impl PhysAddr {
+    pub const fn try_new(addr: u64) -> Option<Self> {
+        if addr >> PHYS_ADDR_BITS == 0 {
+            Some(Self(addr))
+        } else {
+            None
+        }
+    }
+
+    pub fn new(addr: u64) -> Self {
+        Self::try_new(addr).expect("physical address exceeds 52 bits")
+    }
+
+    // Drop bits above 52
+    pub const fn new_truncate(addr: u64) -> Self {
+        Self(addr & ((1 << PHYS_ADDR_BITS) - 1))
+    }
+
+    pub const fn zero() -> Self {
+        Self(0)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn align_down(self, align: u64) -> Self {
+        Self(align_down(self.0, align))
+    }
+
+    pub fn align_up(self, align: u64) -> Self {
+        Self::new(align_up(self.0, align))
+    }
+
+    pub fn is_aligned(self, align: u64) -> bool {
+        self.0 % align == 0
+    }
+}
+
+// Virtual address in canonical form (bits 48..64 are copies of bit 47)
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct VirtAddr(u64);
+
+impl VirtAddr {
+    pub const fn try_new(addr: u64) -> Option<Self> {
+        let truncated = Self::new_truncate(addr);
+        if truncated.0 == addr {
+            Some(truncated)
+        } else {
+            None
+        }
+    }
+
+    pub fn new(addr: u64) -> Self {
+        Self::try_new(addr).expect("non canonical virtual address")
+    }
+
+    // Overwrite bits 48..64 with sign extension of bit 47
+    pub const fn new_truncate(addr: u64) -> Self {
+        Self((((addr << 16) as i64) >> 16) as u64)
+    }
+
+    pub const fn zero() -> Self {
+        Self(0)
+    }
+
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        Self::new(ptr as u64)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    pub fn page_offset(self) -> u64 {
+        self.0 % PAGE_SIZE
+    }
+
+    pub fn align_down(self, align: u64) -> Self {
+        Self::new_truncate(align_down(self.0, align))
+    }
+
+    pub fn align_up(self, align: u64) -> Self {
+        Self::new_truncate(align_up(self.0, align))
+    }
+
+    pub fn is_aligned(self, align: u64) -> bool {
+        self.0 % align == 0
+    }
+}
+
+fn align_down(addr: u64, align: u64) -> u64 {
+    addr - addr % align
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    align_down(addr + align - 1, align)
+}
+
+// The kernel is linked at this base (cf. linker.ld) and boot code maps the first 1GB here
+pub const KERNEL_OFFSET: u64 = 0xffff_ffff_8000_0000;
+pub const KERNEL_WINDOW_SIZE: u64 = 1 << 30;
+
+// Boot code maps the first 4GB of physical memory here and `remap_kernel` keeps it
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+pub const PHYSICAL_MEMORY_SIZE: u64 = 1 << 32;
+
+// Kernel windows are kept in the higher half (p4 index), so the lower half is left to users
+//   256  physical memory mapping
+//   320  kernel stacks (cf. `stack_allocator`)
+//   352  MMIO (cf. `mmio`)
+//   384  temporary pages
+//   510  recursive mapping
+//   511  kernel image
+pub const TEMPORARY_PAGE_REGION_START: u64 = 0xffff_c000_0000_0000;
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    assert!(
+        addr.as_u64() < PHYSICAL_MEMORY_SIZE,
+        "{:?} is outside the physical memory mapping",
+        addr
+    );
+    VirtAddr::new(addr.as_u64() + PHYSICAL_MEMORY_OFFSET)
+}
+
+// Only for the physical memory mapping and the kernel image (cf. `paging::translate` for others)
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    let addr = addr.as_u64();
+    let window = |offset: u64, size: u64| addr.checked_sub(offset).filter(|&x| x < size);
+    window(PHYSICAL_MEMORY_OFFSET, PHYSICAL_MEMORY_SIZE)
+        .or_else(|| window(KERNEL_OFFSET, KERNEL_WINDOW_SIZE))
+        .map(PhysAddr::new)
+}
+
+// Physical memory frame of 4KB
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Frame {
+    number: u64,
+}
+
+impl Frame {
+    pub const fn from_number(number: u64) -> Self {
+        Self { number }
+    }
+
+    pub fn containing_address(addr: PhysAddr) -> Self {
+        Self::from_number(addr.as_u64() / PAGE_SIZE)
+    }
+
+    pub fn from_start_address(addr: PhysAddr) -> Option<Self> {
+        if addr.is_aligned(PAGE_SIZE) {
+            Some(Self::containing_address(addr))
+        } else {
+            None
+        }
+    }
+
+    pub const fn number(self) -> u64 {
+        self.number
+    }
+
+    pub fn start_address(self) -> PhysAddr {
+        PhysAddr::new(self.number * PAGE_SIZE)
+    }
+
+    // [start, end)
+    pub fn range(start: Frame, end: Frame) -> FrameRange {
+        FrameRange { start, end }
+    }
+}
+
+// Virtual memory page of 4KB (36 bits page number)
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Page {
+    number: u64,
+}
+
+const PAGE_NUMBER_MASK: u64 = 0o777_777_777_777;
+
+impl Page {
+    pub fn from_number(number: u64) -> Self {
+        assert!(number <= PAGE_NUMBER_MASK, "invalid page number {}", number);
+        Self { number }
+    }
+
+    pub fn containing_address(addr: VirtAddr) -> Self {
+        Self::from_number((addr.as_u64() / PAGE_SIZE) & PAGE_NUMBER_MASK)
+    }
+
+    pub fn from_start_address(addr: VirtAddr) -> Option<Self> {
+        if addr.is_aligned(PAGE_SIZE) {
+            Some(Self::containing_address(addr))
+        } else {
+            None
+        }
+    }
+
+    pub const fn number(self) -> u64 {
+        self.number
+    }
+
+    pub fn start_address(self) -> VirtAddr {
+        VirtAddr::new_truncate(self.number * PAGE_SIZE)
+    }
+
+    pub fn p4_index(self) -> usize {
+        ((self.number >> (9 * 3)) & 0o777) as usize
+    }
+
+    pub fn p3_index(self) -> usize {
+        ((self.number >> (9 * 2)) & 0o777) as usize
+    }
+
+    pub fn p2_index(self) -> usize {
+        ((self.number >> (9 * 1)) & 0o777) as usize
+    }
+
+    pub fn p1_index(self) -> usize {
+        ((self.number >> (9 * 0)) & 0o777) as usize
+    }
+
+    // [start, end)
+    pub fn range(start: Page, end: Page) -> PageRange {
+        PageRange { start, end }
+    }
+}
+
+// Arithmetic by bytes (addresses) or by number of frames/pages
+macro_rules! impl_arithmetic {
+    ($type:ident, $field:tt, $new:path) => {
+        impl Add<u64> for $type {
+            type Output = Self;
+
+            fn add(self, other: u64) -> Self {
+                $new(self.$field + other)
+            }
+        }
+
+        impl AddAssign<u64> for $type {
+            fn add_assign(&mut self, other: u64) {
+                *self = *self + other;
+            }
+        }
+
+        impl Sub<u64> for $type {
+            type Output = Self;
+
+            fn sub(self, other: u64) -> Self {
+                $new(self.$field - other)
+            }
+        }
+
+        impl SubAssign<u64> for $type {
+            fn sub_assign(&mut self, other: u64) {
+                *self = *self - other;
+            }
+        }
+
+        impl Sub<$type> for $type {
+            type Output = u64;
+
+            fn sub(self, other: $type) -> u64 {
+                self.$field - other.$field
+            }
+        }
+    };
+}
+
+impl_arithmetic!(PhysAddr, 0, PhysAddr::new);
+impl_arithmetic!(VirtAddr, 0, VirtAddr::new);
+impl_arithmetic!(Frame, number, Frame::from_number);
+impl_arithmetic!(Page, number, Page::from_number);
+
+impl fmt::Debug for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PhysAddr(0x{:x})", self.0)
+    }
+}
+
+impl fmt::Debug for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VirtAddr(0x{:x})", self.0)
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame({})", self.number)
+    }
+}
+
+impl fmt::Debug for Page {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Page({})", self.number)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameRange {
+    pub start: Frame,
+    pub end: Frame,
+}
+
+impl FrameRange {
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+}
+
+impl Iterator for FrameRange {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.is_empty() {
+            return None;
+        }
+        let frame = self.start;
+        self.start += 1;
+        Some(frame)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PageRange {
+    pub start: Page,
+    pub end: Page,
+}
+
+impl PageRange {
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+}
+
+impl Iterator for PageRange {
+    type Item = Page;
+
+    fn next(&mut self) -> Option<Page> {
+        if self.is_empty() {
+            return None;
+        }
+        let page = self.start;
+        self.start += 1;
+        Some(page)
+    }
+}
+
+//
+// Physical memory map
+//
+
+// Later kinds take precedence where regions overlap, so that memory is usable only if
+// nothing else claims it:
+//   - firmware kinds which are not usable override usable entries reported over them,
+//   - memory allocated by the boot process (`is_occupied`) overrides any firmware kind,
+//     which carves the kernel image, modules etc. out of usable memory
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryRegionKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    Kernel,
+    BootInfo,
+    Module,
+    Framebuffer,
+}
+
+impl MemoryRegionKind {
+    // `type` of multiboot2 memory map entry
+    pub fn from_multiboot(type_: u32) -> Self {
+        match type_ {
+            1 => Self::Usable,
+            3 => Self::AcpiReclaimable,
+            4 => Self::AcpiNvs,
+            5 => Self::BadMemory,
+            _ => Self::Reserved,
+        }
+    }
+
+    // Allocated by the boot process (as opposed to reported by firmware)
+    pub fn is_occupied(self) -> bool {
+        self >= Self::Kernel
+    }
+}
+
+// Physical range [start, end)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    pub fn new(start: PhysAddr, end: PhysAddr, kind: MemoryRegionKind) -> Self {
+        Self { start, end, kind }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn range(&self) -> (u64, u64) {
+        (self.start.as_u64(), self.end.as_u64())
+    }
+}
+
+pub const MAX_MEMORY_REGIONS: usize = 64;
+
+// Sorted regions without overlap, where adjacent regions of the same kind are merged
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    len: usize,
+}
+
+const EMPTY_REGION: MemoryRegion = MemoryRegion {
+    start: PhysAddr::zero(),
+    end: PhysAddr::zero(),
+    kind: MemoryRegionKind::Reserved,
+};
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        Self {
+            regions: [EMPTY_REGION; MAX_MEMORY_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+
+    // Part overlapping with existing regions gets the kind of the higher precedence
+    pub fn add(&mut self, region: MemoryRegion) {
+        if region.is_empty() {
+            return;
+        }
+        let old = *self;
+        let inputs = || old.regions().iter().chain(core::iter::once(&region));
+        self.len = 0;
+
+        // Walk boundaries in order and pick the winning kind for each interval between them
+        let mut point = inputs().map(|r| r.start).min().unwrap();
+        loop {
+            let next = inputs()
+                .flat_map(|r| core::array::IntoIter::new([r.start, r.end]))
+                .filter(|&x| x > point)
+                .min();
+            let next = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let kind = inputs().filter(|r| r.contains(point)).map(|r| r.kind).max();
+            if let Some(kind) = kind {
+                self.push(MemoryRegion::new(point, next, kind));
+            }
+            point = next;
+        }
+    }
+
+    fn push(&mut self, region: MemoryRegion) {
+        if region.is_empty() {
+            return;
+        }
+        if let Some(last) = self.regions[..self.len].last_mut() {
+            if last.end == region.start && last.kind == region.kind {
+                last.end = region.end;
+                return;
+            }
+        }
+        // Regions are pushed in order, so the last one absorbs the rest when the map is full.
+        // It never becomes usable, so this only loses memory (including any gap in between).
+        if self.len == MAX_MEMORY_REGIONS {
+            let last = &mut self.regions[self.len - 1];
+            last.end = region.end;
+            last.kind = last.kind.max(region.kind).max(MemoryRegionKind::Reserved);
+            return;
+        }
+        self.regions[self.len] = region;
+        self.len += 1;
+    }
+
+    // Ranges of the given kind (e.g. to seed frame allocators), which borrow the map
+    // so that cloning them is cheap
+    pub fn ranges(
+        &self,
+        filter: fn(MemoryRegionKind) -> bool,
+    ) -> impl Iterator<Item = (u64, u64)> + Clone + '_ {
+        self.regions()
+            .iter()
+            .filter(move |r| filter(r.kind))
+            .map(MemoryRegion::range)
+    }
+
+    pub fn usable(&self) -> impl Iterator<Item = (u64, u64)> + Clone + '_ {
+        self.ranges(|kind| kind == MemoryRegionKind::Usable)
+    }
+
+    // Memory used by boot process
+    pub fn occupied(&self) -> impl Iterator<Item = (u64, u64)> + Clone + '_ {
+        self.ranges(MemoryRegionKind::is_occupied)
+    }
+}
+
+pub trait FrameAllocator {
+    fn allocate(&mut self) -> Option<Frame>;
+
+    // Drop one reference (the frame is freed when no reference is left).
+    // Frames are leaked if `can_deallocate` is false.
+    fn deallocate(&mut self, frame: Frame);
+
+    // Whether `deallocate` makes frames available again
+    fn can_deallocate(&self) -> bool {
+        true
+    }
+
+    // Number of mappings sharing the frame (e.g. copy-on-write).
+    // By default every allocated frame has a single owner.
+    fn reference_count(&self, _frame: Frame) -> usize {
+        1
+    }
+
+    fn add_reference(&mut self, frame: Frame) {
+        panic!("frame sharing is not supported ({:?})", frame);
+    }
+
+    // None if the allocator doesn't keep track of free frames
+    fn frame_stats(&self) -> Option<FrameStats> {
+        None
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: u64,
+    pub free: u64,
+    pub largest_free_run: u64, // contiguous free frames
+}
+
+impl FrameStats {
+    pub fn used(&self) -> u64 {
+        self.total - self.free
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames: total = {}, used = {}, free = {}, largest free run = {}",
+            self.total,
+            self.used(),
+            self.free,
+            self.largest_free_run
+        )
+    }
+}
+
+// Print frame allocator and heap statistics
+pub fn dump_memory_stats<W: fmt::Write>(
+    w: &mut W,
+    frame_allocator: &dyn FrameAllocator,
+    heap: Option<heap_allocator::HeapStats>,
+) -> fmt::Result {
+    match frame_allocator.frame_stats() {
+        Some(stats) => writeln!(w, "{}", stats)?,
+        None => writeln!(w, "frames: not tracked")?,
+    }
+    if let Some(stats) = heap {
+        writeln!(w, "{}", stats)?;
+    }
+    Ok(())
+}
+
+// Frame allocator shared by heap allocators which map pages on demand
+pub type SharedFrameAllocator = &'static Mutex<dyn FrameAllocator + Send>;
+
+// Allows a statically allocated allocator to be initialized at runtime
+impl<A: FrameAllocator> FrameAllocator for Option<A> {
+    fn allocate(&mut self) -> Option<Frame> {
+        self.as_mut()?.allocate()
+    }
+
+    fn deallocate(&mut self, frame: Frame) {
+        self.as_mut()
+            .expect("frame allocator is not initialized")
+            .deallocate(frame)
+    }
+
+    fn can_deallocate(&self) -> bool {
+        self.as_ref()
+            .map_or(false, |allocator| allocator.can_deallocate())
+    }
+
+    fn reference_count(&self, frame: Frame) -> usize {
+        self.as_ref()
+            .expect("frame allocator is not initialized")
+            .reference_count(frame)
+    }
+
+    fn add_reference(&mut self, frame: Frame) {
+        self.as_mut()
+            .expect("frame allocator is not initialized")
+            .add_reference(frame)
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        self.as_ref()?.frame_stats()
+    }
+}
+
+// Frame 0 is never allocated (its address would be a null pointer through the boot time
+// identity map), which every allocator keeps the same
+const FIRST_FRAME: u64 = 1;
+
+pub struct SimpleFrameAllocator<I1, I2> {
+    index: u64,
+    usable: I1,
+    occupied: I2,
+    usable_max: u64,
+}
+
+impl<I1, I2> SimpleFrameAllocator<I1, I2>
+where
+    I1: Iterator<Item = (u64, u64)> + Clone,
+    I2: Iterator<Item = (u64, u64)> + Clone,
+{
+    pub fn new(usable: I1, occupied: I2) -> Self {
+        let usable_max = usable.clone().map(|(_, hi)| hi).max().unwrap_or(0);
+        Self {
+            index: FIRST_FRAME,
+            usable,
+            occupied,
+            usable_max,
+        }
+    }
+}
+
+impl<I1, I2> FrameAllocator for SimpleFrameAllocator<I1, I2>
+where
+    I1: Iterator<Item = (u64, u64)> + Clone,
+    I2: Iterator<Item = (u64, u64)> + Clone,
+{
+    fn allocate(&mut self) -> Option<Frame> {
+        loop {
+            let index = self.index;
+            let addr_lo = index * PAGE_SIZE;
+            let addr_hi = addr_lo + PAGE_SIZE;
+            if addr_lo >= self.usable_max {
+                return None;
+            }
+            self.index += 1;
+            let usable = self
+                .usable
+                .clone()
+                .any(|(lo, hi)| lo <= addr_lo && addr_hi <= hi);
+            let occupied = self
+                .occupied
+                .clone()
+                .any(|(lo, hi)| lo <= addr_lo && addr_hi <= hi);
+            if !usable || occupied {
+                continue;
+            }
+            return Some(Frame::from_number(index));
+        }
+    }
+
+    // Bump allocator cannot reclaim frames (use BitmapFrameAllocator instead)
+    fn deallocate(&mut self, frame: Frame) {
+        debug_assert!(false, "SimpleFrameAllocator leaks {:?}", frame);
+    }
+
+    fn can_deallocate(&self) -> bool {
+        false
+    }
+}
+
+//
+// Bitmap frame allocator
+//
+
+const BITS_PER_WORD: u64 = 64;
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64], // 1 bit per frame (set = used)
+    shared: &'static mut [u16], // references in addition to the owner of used frame
+    next: usize,                // every word before `next` is full
+    total: u64,
+    free: u64,
+}
+
+fn overlaps(lo: u64, hi: u64, ranges: impl Iterator<Item = (u64, u64)>) -> Option<u64> {
+    ranges
+        .filter(|&(r_lo, r_hi)| r_lo < hi && lo < r_hi)
+        .map(|(_, r_hi)| r_hi)
+        .max()
+}
+
+// Find `size` bytes of usable memory which is not occupied (from `FIRST_FRAME`)
+// within the physical memory mapping, where it's accessed through `phys_to_virt`
+fn find_storage<I1, I2>(usable: I1, occupied: I2, size: u64) -> Option<(u64, u64)>
+where
+    I1: Iterator<Item = (u64, u64)> + Clone,
+    I2: Iterator<Item = (u64, u64)> + Clone,
+{
+    usable.clone().find_map(|(lo, hi)| {
+        let hi = core::cmp::min(hi, PHYSICAL_MEMORY_SIZE);
+        let mut start = ceil_align(core::cmp::max(lo, FIRST_FRAME * PAGE_SIZE), PAGE_SIZE);
+        while start + size <= hi {
+            match overlaps(start, start + size, occupied.clone()) {
+                Some(occupied_hi) => start = ceil_align(occupied_hi, PAGE_SIZE),
+                None => return Some((start, start + size)),
+            }
+        }
+        None
+    })
+}
+
+// Frames are accessed through `phys_to_virt` (e.g. page tables and free list nodes),
+// so usable memory beyond the physical memory mapping is not managed
+fn clip_to_physical_memory<I>(usable: I) -> impl Iterator<Item = (u64, u64)> + Clone
+where
+    I: Iterator<Item = (u64, u64)> + Clone,
+{
+    usable
+        .map(|(lo, hi)| (lo, core::cmp::min(hi, PHYSICAL_MEMORY_SIZE)))
+        .filter(|&(lo, hi)| lo < hi)
+}
+
+// Enumerate `(frame, used)` for every frame within usable memory
+fn usable_frames<I1, I2>(
+    usable: I1,
+    occupied: I2,
+    storage: (u64, u64),
+) -> impl Iterator<Item = (Frame, bool)>
+where
+    I1: Iterator<Item = (u64, u64)> + Clone,
+    I2: Iterator<Item = (u64, u64)> + Clone,
+{
+    usable
+        .flat_map(|(lo, hi)| {
+            let start = PhysAddr::new(ceil_align(lo, PAGE_SIZE));
+            let end = PhysAddr::new(hi);
+            Frame::range(
+                Frame::containing_address(start),
+                Frame::containing_address(end),
+            )
+        })
+        .map(move |frame| {
+            let addr_lo = frame.start_address().as_u64();
+            let addr_hi = addr_lo + PAGE_SIZE;
+            let used = frame.number() < FIRST_FRAME
+                || overlaps(addr_lo, addr_hi, occupied.clone()).is_some()
+                || overlaps(addr_lo, addr_hi, core::iter::once(storage)).is_some();
+            (frame, used)
+        })
+}
+
+impl BitmapFrameAllocator {
+    // Bitmap and reference counts are stored in the first usable memory which is not occupied
+    pub fn new<I1, I2>(usable: I1, occupied: I2) -> Self
+    where
+        I1: Iterator<Item = (u64, u64)> + Clone,
+        I2: Iterator<Item = (u64, u64)> + Clone,
+    {
+        let usable = clip_to_physical_memory(usable);
+        let usable_max = usable.clone().map(|(_, hi)| hi).max().unwrap_or(0);
+        let num_frames = usable_max / PAGE_SIZE;
+        let num_words = ((num_frames + BITS_PER_WORD - 1) / BITS_PER_WORD) as usize;
+        let size = (num_words * 8) as u64 + num_frames * 2;
+        let storage = find_storage(usable.clone(), occupied.clone(), size)
+            .expect("no memory for frame bitmap");
+        let start = phys_to_virt(PhysAddr::new(storage.0));
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), num_words) };
+        let shared = unsafe {
+            let ptr = (start + (num_words * 8) as u64).as_mut_ptr();
+            core::slice::from_raw_parts_mut(ptr, num_frames as usize)
+        };
+
+        // Mark everything used, then release usable frames
+        bitmap.iter_mut().for_each(|x| *x = !0);
+        shared.iter_mut().for_each(|x| *x = 0);
+        let mut allocator = Self {
+            bitmap,
+            shared,
+            next: 0,
+            total: 0,
+            free: 0,
+        };
+        for (frame, used) in usable_frames(usable, occupied, storage) {
+            allocator.total += 1;
+            if !used {
+                allocator.deallocate(frame);
+            }
+        }
+        allocator
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.total
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free
+    }
+
+    // Frames outside managed memory (e.g. MMIO) are never free
+    pub fn is_used(&self, frame: Frame) -> bool {
+        match self.bit(frame) {
+            Some((word, bit)) => self.bitmap[word] & bit != 0,
+            None => true,
+        }
+    }
+
+    pub fn manages(&self, frame: Frame) -> bool {
+        (frame.number() as usize) < self.shared.len()
+    }
+
+    fn bit(&self, frame: Frame) -> Option<(usize, u64)> {
+        if self.manages(frame) {
+            Some(frame_to_bit(frame))
+        } else {
+            None
+        }
+    }
+
+    // Physical memory range of the bitmap and reference counts
+    pub fn storage(&self) -> (PhysAddr, PhysAddr) {
+        let lo = virt_to_phys(VirtAddr::from_ptr(self.bitmap.as_ptr())).unwrap();
+        (
+            lo,
+            lo + (self.bitmap.len() * 8 + self.shared.len() * 2) as u64,
+        )
+    }
+}
+
+fn frame_to_bit(frame: Frame) -> (usize, u64) {
+    let number = frame.number();
+    (
+        (number / BITS_PER_WORD) as usize,
+        1 << (number % BITS_PER_WORD),
+    )
+}
+
+fn ceil_align(addr: u64, align: u64) -> u64 {
+    addr + (align - (addr % align)) % align
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate(&mut self) -> Option<Frame> {
+        while self.next < self.bitmap.len() {
+            let word = self.bitmap[self.next];
+            if word != !0 {
+                let bit = (!word).trailing_zeros() as u64;
+                self.bitmap[self.next] |= 1 << bit;
+                self.free -= 1;
+                return Some(Frame::from_number((self.next as u64) * BITS_PER_WORD + bit));
+            }
+            self.next += 1;
+        }
+        None
+    }
+
+    fn deallocate(&mut self, frame: Frame) {
+        let (word, bit) = self
+            .bit(frame)
+            .unwrap_or_else(|| panic!("{:?} is outside managed memory", frame));
+        assert!(self.bitmap[word] & bit != 0, "double free of {:?}", frame);
+        let shared = &mut self.shared[frame.number() as usize];
+        if *shared > 0 {
+            *shared -= 1;
+            return;
+        }
+        self.bitmap[word] &= !bit;
+        self.next = core::cmp::min(self.next, word);
+        self.free += 1;
+    }
+
+    fn reference_count(&self, frame: Frame) -> usize {
+        if self.manages(frame) && self.is_used(frame) {
+            self.shared[frame.number() as usize] as usize + 1
+        } else {
+            0
+        }
+    }
+
+    fn add_reference(&mut self, frame: Frame) {
+        assert!(
+            self.manages(frame) && self.is_used(frame),
+            "{:?} is not allocated",
+            frame
+        );
+        let shared = &mut self.shared[frame.number() as usize];
+        *shared = shared.checked_add(1).expect("too many references");
+    }
+
+    fn frame_stats(&self) -> Option<FrameStats> {
+        // Frames outside usable memory are marked used, so they break runs as well
+        let (mut run, mut largest) = (0, 0);
+        for &word in self.bitmap.iter() {
+            if word == 0 {
+                run += BITS_PER_WORD;
+                continue;
+            }
+            for bit in 0..BITS_PER_WORD {
+                if word & (1 << bit) == 0 {
+                    run += 1;
+                } else {
+                    largest = core::cmp::max(largest, run);
+                    run = 0;
+                }
+            }
+        }
+        Some(FrameStats {
+            total: self.total,
+            free: self.free,
+            largest_free_run: core::cmp::max(largest, run),
+        })
+    }
+}
+
+//
+// Buddy frame allocator (cf. https://en.wikipedia.org/wiki/Buddy_memory_allocation)
+//
+
+pub const BUDDY_MAX_ORDER: usize = 10; // 2^10 frames = 4MB
+const BUDDY_NUM_ORDERS: usize = BUDDY_MAX_ORDER + 1;
+const BUDDY_NIL: u64 = !0;
+
+// Free list node stored in the first frame of each free block (linked by frame number)
+#[repr(C)]
+struct BuddyBlock {
+    next: u64,
+    prev: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BuddyStats {
+    pub splits: u64,
+    pub merges: u64,
+    pub free_frames: u64,
+    pub free_blocks: [u64; BUDDY_NUM_ORDERS],
+}
+
+pub struct BuddyFrameAllocator {
+    // 1 bit per block for each order (set = block is in the free list)
+    bitmap: &'static mut [u64],
+    bitmap_offsets: [usize; BUDDY_NUM_ORDERS],
+    shared: &'static mut [u16], // references in addition to the owner of used frame
+    num_frames: u64,
+    total: u64, // usable frames
+    free_lists: [u64; BUDDY_NUM_ORDERS],
+    stats: BuddyStats,
+}
+
+impl BuddyFrameAllocator {
+    // Bitmap and reference counts are stored in the first usable memory which is not occupied.
+    // Free list nodes are written into free frames through `phys_to_virt`,
+    // so usable memory beyond the physical memory mapping is ignored.
+    pub fn new<I1, I2>(usable: I1, occupied: I2) -> Self
+    where
+        I1: Iterator<Item = (u64, u64)> + Clone,
+        I2: Iterator<Item = (u64, u64)> + Clone,
+    {
+        let usable = clip_to_physical_memory(usable);
+        let usable_max = usable.clone().map(|(_, hi)| hi).max().unwrap_or(0);
+        let num_frames = usable_max / PAGE_SIZE;
+        let mut bitmap_offsets = [0; BUDDY_NUM_ORDERS];
+        let mut num_words = 0;
+        for order in 0..BUDDY_NUM_ORDERS {
+            bitmap_offsets[order] = num_words;
+            num_words += (((num_frames >> order) + BITS_PER_WORD - 1) / BITS_PER_WORD) as usize;
+        }
+        let size = (num_words * 8) as u64 + num_frames * 2;
+        let storage = find_storage(usable.clone(), occupied.clone(), size)
+            .expect("no memory for buddy bitmap");
+        let start = phys_to_virt(PhysAddr::new(storage.0));
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), num_words) };
+        let shared = unsafe {
+            let ptr = (start + (num_words * 8) as u64).as_mut_ptr();
+            core::slice::from_raw_parts_mut(ptr, num_frames as usize)
+        };
+        bitmap.iter_mut().for_each(|x| *x = 0);
+        shared.iter_mut().for_each(|x| *x = 0);
+
+        let mut allocator = Self {
+            bitmap,
+            bitmap_offsets,
+            shared,
+            num_frames,
+            total: 0,
+            free_lists: [BUDDY_NIL; BUDDY_NUM_ORDERS],
+            stats: BuddyStats::default(),
+        };
+
+        // Release frames one by one and let them merge
+        for (frame, used) in usable_frames(usable, occupied, storage) {
+            allocator.total += 1;
+            if !used {
+                allocator.free_contiguous(frame, 0);
+            }
+        }
+        allocator.stats.splits = 0;
+        allocator.stats.merges = 0;
+        allocator
+    }
+
+    pub fn stats(&self) -> BuddyStats {
+        self.stats
+    }
+
+    // Allocate 2^order contiguous frames aligned to 2^order
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<Frame> {
+        assert!(order <= BUDDY_MAX_ORDER);
+        let found = (order..BUDDY_NUM_ORDERS).find(|&k| self.free_lists[k] != BUDDY_NIL)?;
+        let frame = self.free_lists[found];
+        self.remove(frame, found);
+        for k in (order..found).rev() {
+            self.push(frame + (1 << k), k);
+            self.stats.splits += 1;
+        }
+        self.stats.free_frames -= 1 << order;
+        Some(Frame::from_number(frame))
+    }
+
+    pub fn free_contiguous(&mut self, frame: Frame, order: usize) {
+        assert!(order <= BUDDY_MAX_ORDER);
+        let frame = frame.number();
+        assert!(frame % (1 << order) == 0, "unaligned block {}", frame);
+        assert!(
+            !self.is_within_free(frame, order),
+            "double free of block {}",
+            frame
+        );
+        self.stats.free_frames += 1 << order;
+        let mut frame = frame;
+        let mut order = order;
+        while order < BUDDY_MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            self.stats.merges += 1;
+            frame = core::cmp::min(frame, buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    fn bit(&self, frame: u64, order: usize) -> Option<(usize, u64)> {
+        let block = frame >> order;
+        if block >= (self.num_frames >> order) {
+            return None;
+        }
+        let word = self.bitmap_offsets[order] + (block / BITS_PER_WORD) as usize;
+        Some((word, 1 << (block % BITS_PER_WORD)))
+    }
+
+    // Frames outside managed memory (e.g. MMIO) are never free
+    fn is_used(&self, frame: Frame) -> bool {
+        frame.number() >= self.num_frames || !self.is_within_free(frame.number(), 0)
+    }
+
+    // Whether the block or a larger block containing it is free
+    fn is_within_free(&self, frame: u64, order: usize) -> bool {
+        (order..BUDDY_NUM_ORDERS).any(|k| self.is_free(frame >> k << k, k))
+    }
+
+    fn is_free(&self, frame: u64, order: usize) -> bool {
+        match self.bit(frame, order) {
+            Some((word, bit)) => self.bitmap[word] & bit != 0,
+            None => false,
+        }
+    }
+
+    fn block<'a>(frame: u64) -> &'a mut BuddyBlock {
+        let addr = phys_to_virt(Frame::from_number(frame).start_address());
+        unsafe { address_cast_mut(addr.as_u64() as usize) }
+    }
+
+    fn push(&mut self, frame: u64, order: usize) {
+        let (word, bit) = self.bit(frame, order).unwrap();
+        self.bitmap[word] |= bit;
+        let head = self.free_lists[order];
+        let block = Self::block(frame);
+        block.next = head;
+        block.prev = BUDDY_NIL;
+        if head != BUDDY_NIL {
+            Self::block(head).prev = frame;
+        }
+        self.free_lists[order] = frame;
+        self.stats.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, frame: u64, order: usize) {
+        let (word, bit) = self.bit(frame, order).unwrap();
+        self.bitmap[word] &= !bit;
+        let (next, prev) = {
+            let block = Self::block(frame);
+            (block.next, block.prev)
+        };
+        if prev == BUDDY_NIL {
+            self.free_lists[order] = next;
+        } else {
+            Self::block(prev).next = next;
+        }
+        if next != BUDDY_NIL {
+            Self::block(next).prev = prev;
+        }
+        self.stats.free_blocks[order] -= 1;
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn allocate(&mut self) -> Option<Frame> {
+        self.allocate_contiguous(0)
+    }
+
+    fn deallocate(&mut self, frame: Frame) {
+        if let Some(shared) = self.shared.get_mut(frame.number() as usize) {
+            if *shared > 0 {
+                *shared -= 1;
+                return;
+            }
+        }
+        self.free_contiguous(frame, 0);
+    }
+
+    fn reference_count(&self, frame: Frame) -> usize {
+        match self.shared.get(frame.number() as usize) {
+            Some(&shared) if self.is_used(frame) => shared as usize + 1,
+            _ => 0,
+        }
+    }
+
+    fn add_reference(&mut self, frame: Frame) {
+        assert!(
+            frame.number() < self.num_frames && self.is_used(frame),
+            "{:?} is not allocated",
+            frame
+        );
+        let shared = &mut self.shared[frame.number() as usize];
+        *shared = shared.checked_add(1).expect("too many references");
+    }
+
+    // Largest free run is the largest free block (adjacent blocks are not counted together)
+    fn frame_stats(&self) -> Option<FrameStats> {
+        let order = (0..BUDDY_NUM_ORDERS)
+            .rev()
+            .find(|&k| self.stats.free_blocks[k] > 0);
+        Some(FrameStats {
+            total: self.total,
+            free: self.stats.free_frames,
+            largest_free_run: order.map_or(0, |k| 1 << k),
+        })
+    }
+}
+
+pub mod paging {
+    use crate::asm::{
+        cpuid, flush_tlb, invlpg, rdmsr, read_cr0, read_cr3, write_cr0, write_cr3,
+        CR0_WRITE_PROTECT, EFER, EFER_NXE, IA32_PAT,
+    };
+    use crate::memory::mmio::is_pat_supported;
+    use crate::memory::{phys_to_virt, Frame, FrameAllocator, Page, PageRange, PhysAddr, VirtAddr};
+    use crate::memory::{
+        PAGE_SIZE, PHYSICAL_MEMORY_OFFSET, PHYSICAL_MEMORY_SIZE, TEMPORARY_PAGE_REGION_START,
+    };
+    use crate::multiboot2::{BootInfo, SectionHeader};
+    use crate::util::address_cast_mut;
+    use core::fmt;
+    use core::ops::{BitAnd, BitOr, BitOrAssign, Deref, DerefMut, Not};
+
+    // cf. https://wiki.osdev.org/Paging#64-Bit_Paging
+    #[derive(Copy, Clone, PartialEq, Eq)]
+    pub struct EntryFlags(u64);
+
+    impl EntryFlags {
+        pub const PRESENT: Self = Self(1 << 0);
+        pub const WRITABLE: Self = Self(1 << 1);
+        pub const USER_ACCESSIBLE: Self = Self(1 << 2);
+        pub const WRITE_THROUGH: Self = Self(1 << 3);
+        pub const NO_CACHE: Self = Self(1 << 4);
+        pub const ACCESSED: Self = Self(1 << 5);
+        pub const DIRTY: Self = Self(1 << 6);
+        pub const HUGE_PAGE: Self = Self(1 << 7);
+        pub const GLOBAL: Self = Self(1 << 8);
+        // Bits 9..12 are available for software
+        pub const COPY_ON_WRITE: Self = Self(1 << 9);
+        pub const NO_EXECUTE: Self = Self(1 << 63);
+
+        const ALL: Self = Self(0xfff | (1 << 63));
+
+        pub const fn empty() -> Self {
+            Self(0)
+        }
+
+        pub const fn bits(&self) -> u64 {
+            self.0
+        }
+
+        pub const fn from_bits_truncate(bits: u64) -> Self {
+            Self(bits & Self::ALL.0)
+        }
+
+        pub const fn union(self, other: Self) -> Self {
+            Self(self.0 | other.0)
+        }
+
+        pub const fn contains(&self, other: Self) -> bool {
+            self.0 & other.0 == other.0
+        }
+
+        pub fn insert(&mut self, other: Self) {
+            self.0 |= other.0;
+        }
+
+        pub fn remove(&mut self, other: Self) {
+            self.0 &= !other.0;
+        }
+
+        // Page attribute table index bit, only valid for P1 entries.
+        // It's the same bit as HUGE_PAGE of P2/P3 entries, so it's not one of the constants.
+        pub const fn p1_pat() -> Self {
+            Self::HUGE_PAGE
+        }
+
+        // Flags of an entry of P`level` table, bit 7 is printed as PAT or HUGE_PAGE accordingly
+        pub const fn at_level(self, level: usize) -> LevelFlags {
+            LevelFlags { flags: self, level }
+        }
+    }
+
+    impl BitOr for EntryFlags {
+        type Output = Self;
+
+        fn bitor(self, other: Self) -> Self {
+            Self(self.0 | other.0)
+        }
+    }
+
+    impl BitOrAssign for EntryFlags {
+        fn bitor_assign(&mut self, other: Self) {
+            self.0 |= other.0;
+        }
+    }
+
+    impl BitAnd for EntryFlags {
+        type Output = Self;
+
+        fn bitand(self, other: Self) -> Self {
+            Self(self.0 & other.0)
+        }
+    }
+
+    impl Not for EntryFlags {
+        type Output = Self;
+
+        fn not(self) -> Self {
+            Self(!self.0 & Self::ALL.0)
+        }
+    }
+
+    #[derive(Copy, Clone, PartialEq, Eq)]
+    pub struct LevelFlags {
+        pub flags: EntryFlags,
+        pub level: usize,
+    }
+
+    // Without the level, bit 7 can be either of them
+    impl fmt::Debug for EntryFlags {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt_flags(*self, "HUGE_PAGE/PAT", f)
+        }
+    }
+
+    impl fmt::Debug for LevelFlags {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let name = if self.level == 1 { "PAT" } else { "HUGE_PAGE" };
+            fmt_flags(self.flags, name, f)
+        }
+    }
+
+    fn fmt_flags(flags: EntryFlags, bit7_name: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (EntryFlags::PRESENT, "PRESENT"),
+            (EntryFlags::WRITABLE, "WRITABLE"),
+            (EntryFlags::USER_ACCESSIBLE, "USER_ACCESSIBLE"),
+            (EntryFlags::WRITE_THROUGH, "WRITE_THROUGH"),
+            (EntryFlags::NO_CACHE, "NO_CACHE"),
+            (EntryFlags::ACCESSED, "ACCESSED"),
+            (EntryFlags::DIRTY, "DIRTY"),
+            (EntryFlags::HUGE_PAGE, bit7_name),
+            (EntryFlags::GLOBAL, "GLOBAL"),
+            (EntryFlags::COPY_ON_WRITE, "COPY_ON_WRITE"),
+            (EntryFlags::NO_EXECUTE, "NO_EXECUTE"),
+        ];
+        let mut first = true;
+        for &(flag, name) in names.iter() {
+            if flags.contains(flag) {
+                if !first {
+                    f.write_str(" | ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        if first {
+            f.write_str("(empty)")?;
+        }
+        Ok(())
+    }
+
+    // Physical address bits 12..52
+    const ENTRY_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+    const HUGE_ADDRESS_MASK: u64 = 0x000f_ffff_ffe0_0000;
+
+    #[repr(transparent)]
+    #[derive(Copy, Clone, PartialEq, Eq)]
+    pub struct Entry(u64);
+
+    impl Entry {
+        pub const fn is_unused(&self) -> bool {
+            self.0 == 0
+        }
+
+        pub fn set_unused(&mut self) {
+            self.0 = 0;
+        }
+
+        pub const fn flags(&self) -> EntryFlags {
+            EntryFlags::from_bits_truncate(self.0)
+        }
+
+        pub const fn is_present(&self) -> bool {
+            self.flags().contains(EntryFlags::PRESENT)
+        }
+
+        // Only meaningful for P3 (1GB) and P2 (2MB) entries
+        pub const fn is_huge(&self) -> bool {
+            self.is_present() && self.flags().contains(EntryFlags::HUGE_PAGE)
+        }
+
+        // Frame of P1 entry or child table of P2/P3/P4 entry
+        pub const fn address(&self) -> PhysAddr {
+            PhysAddr::new_truncate(self.0 & ENTRY_ADDRESS_MASK)
+        }
+
+        // Start of 2MB/1GB page (bit 12 is PAT, and bits up to 21 or 30 are reserved)
+        pub const fn huge_address(&self) -> PhysAddr {
+            PhysAddr::new_truncate(self.0 & HUGE_ADDRESS_MASK)
+        }
+
+        pub fn frame(&self) -> Option<Frame> {
+            if self.is_present() {
+                Some(Frame::containing_address(self.address()))
+            } else {
+                None
+            }
+        }
+
+        pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
+            self.0 = frame.start_address().as_u64() | flags.bits();
+        }
+
+        pub fn set_flags(&mut self, flags: EntryFlags) {
+            self.0 = self.address().as_u64() | flags.bits();
+        }
+    }
+
+    impl fmt::Debug for Entry {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Entry")
+                .field("address", &self.address())
+                .field("flags", &self.flags())
+                .finish()
+        }
+    }
+
+    pub const TABLE_SIZE: usize = 1 << 9; // = 512 = 4096 / 8 = PAGE_SIZE / sizeof(Entry)
+
+    pub type Table = [Entry; TABLE_SIZE];
+
+    // Intermediate tables are permissive and leaf entries restrict access
+    const TABLE_FLAGS: EntryFlags = EntryFlags::PRESENT.union(EntryFlags::WRITABLE);
+
+    // NO_EXECUTE is a reserved bit unless EFER.NXE is enabled (see boot.asm)
+    pub fn is_no_execute_enabled() -> bool {
+        rdmsr(EFER) & EFER_NXE != 0
+    }
+
+    fn supported_flags(flags: EntryFlags) -> EntryFlags {
+        let mut flags = flags;
+        if !is_no_execute_enabled() {
+            flags.remove(EntryFlags::NO_EXECUTE);
+        }
+        flags
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum PageSize {
+        Size4KiB,
+        Size2MiB, // P2 entry with HUGE_PAGE
+        Size1GiB, // P3 entry with HUGE_PAGE
+    }
+
+    impl PageSize {
+        pub const fn pages(self) -> u64 {
+            match self {
+                PageSize::Size4KiB => 1,
+                PageSize::Size2MiB => 1 << 9,
+                PageSize::Size1GiB => 1 << 18,
+            }
+        }
+
+        pub const fn bytes(self) -> u64 {
+            self.pages() * PAGE_SIZE
+        }
+
+        // Level of the table whose entry maps a page of this size
+        pub const fn level(self) -> usize {
+            match self {
+                PageSize::Size4KiB => 1,
+                PageSize::Size2MiB => 2,
+                PageSize::Size1GiB => 3,
+            }
+        }
+    }
+
+    // cf. https://en.wikipedia.org/wiki/CPUID#EAX=80000001h:_Extended_Processor_Info_and_Feature_Bits
+    pub fn is_1gib_page_supported() -> bool {
+        cpuid(0x8000_0001).edx & (1 << 26) != 0
+    }
+
+    // p4 entry pointing to p4 itself (the last one is taken by the higher half kernel)
+    pub const RECURSIVE_INDEX: usize = TABLE_SIZE - 2;
+
+    // Every level goes through the recursive entry (bits 48..64 are sign extended)
+    const ACTIVE_P4_TABLE_ADDRESS: VirtAddr = VirtAddr::new_truncate(
+        (RECURSIVE_INDEX << 39
+            | RECURSIVE_INDEX << 30
+            | RECURSIVE_INDEX << 21
+            | RECURSIVE_INDEX << 12) as u64,
+    );
+
+    pub fn get_p4_table<'a>() -> &'a mut Table {
+        unsafe { address_cast_mut(ACTIVE_P4_TABLE_ADDRESS.as_u64() as usize) }
+    }
+
+    pub fn get_child_table_address<'a>(parent: &'a Table, index: usize) -> VirtAddr {
+        let p_addr = parent as *const _ as u64;
+        let c_addr = (p_addr | ((index as u64) << 3)) << 9;
+        VirtAddr::new_truncate(c_addr)
+    }
+
+    pub fn get_child_table<'a, 'b>(parent: &'a Table, index: usize) -> Option<&'b mut Table> {
+        if !parent[index].is_present() || parent[index].is_huge() {
+            return None;
+        }
+        let addr = get_child_table_address(parent, index);
+        Some(unsafe { address_cast_mut(addr.as_u64() as usize) })
+    }
+
+    pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, PageSize)> {
+        let page = Page::containing_address(addr);
+        let p4 = get_p4_table();
+        let p3 = get_child_table(p4, page.p4_index())?;
+        let entry = p3[page.p3_index()];
+        if entry.is_huge() {
+            let offset = addr.as_u64() % PageSize::Size1GiB.bytes();
+            return Some((entry.huge_address() + offset, PageSize::Size1GiB));
+        }
+        let p2 = get_child_table(p3, page.p3_index())?;
+        let entry = p2[page.p2_index()];
+        if entry.is_huge() {
+            let offset = addr.as_u64() % PageSize::Size2MiB.bytes();
+            return Some((entry.huge_address() + offset, PageSize::Size2MiB));
+        }
+        let p1 = get_child_table(p2, page.p2_index())?;
+        let entry = p1[page.p1_index()];
+        if !entry.is_present() {
+            return None;
+        }
+        Some((entry.address() + addr.page_offset(), PageSize::Size4KiB))
+    }
+
+    pub fn virtual_to_physical(addr: VirtAddr) -> Option<PhysAddr> {
+        let (phys_addr, _) = translate(addr)?;
+        Some(phys_addr)
+    }
+
+    // Virtual range mapped to contiguous physical memory with the same page size and flags
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct MappedRange {
+        pub start: VirtAddr,
+        pub end: VirtAddr,
+        pub phys: PhysAddr,
+        pub size: PageSize,
+        pub flags: EntryFlags, // effective permissions through all levels
+        pub memory_type: MemoryType,
+    }
+
+    impl MappedRange {
+        fn extend(&mut self, next: &MappedRange) -> bool {
+            let contiguous =
+                self.end == next.start && self.phys + (self.end - self.start) == next.phys;
+            let same = self.size == next.size
+                && self.flags == next.flags
+                && self.memory_type == next.memory_type;
+            if !contiguous || !same {
+                return false;
+            }
+            self.end = next.end;
+            true
+        }
+    }
+
+    impl fmt::Display for MappedRange {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "0x{:016x}-0x{:016x} => 0x{:012x}-0x{:012x} {:?} {:?} {:?}",
+                self.start.as_u64(),
+                self.end.as_u64(),
+                self.phys.as_u64(),
+                (self.phys + (self.end - self.start)).as_u64(),
+                self.size,
+                self.memory_type,
+                self.flags.at_level(self.size.level())
+            )
+        }
+    }
+
+    // cf. https://wiki.osdev.org/Paging#PAT
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum MemoryType {
+        Uncacheable,
+        WriteCombining,
+        WriteThrough,
+        WriteProtected,
+        WriteBack,
+        UncachedMinus,
+        Reserved(u8),
+    }
+
+    // Power-on value of IA32_PAT, which is also the behavior of WRITE_THROUGH and NO_CACHE without PAT
+    const DEFAULT_PAT: u64 = 0x0007_0406_0007_0406;
+
+    fn read_pat() -> u64 {
+        if is_pat_supported() {
+            rdmsr(IA32_PAT)
+        } else {
+            DEFAULT_PAT
+        }
+    }
+
+    // PAT index is (PAT, NO_CACHE, WRITE_THROUGH) where PAT is bit 7 of P1 entry and bit 12 of P2/P3 entry
+    fn memory_type(pat: u64, entry: &Entry, size: PageSize) -> MemoryType {
+        let pat_bit = if size == PageSize::Size4KiB { 7 } else { 12 };
+        let index = (entry.0 >> pat_bit & 1) << 2 | (entry.0 >> 3 & 0b11);
+        match (pat >> (index * 8)) as u8 & 0x7 {
+            0 => MemoryType::Uncacheable,
+            1 => MemoryType::WriteCombining,
+            4 => MemoryType::WriteThrough,
+            5 => MemoryType::WriteProtected,
+            6 => MemoryType::WriteBack,
+            7 => MemoryType::UncachedMinus,
+            encoding => MemoryType::Reserved(encoding),
+        }
+    }
+
+    // Writable/user only if every level allows it, no execute if any level forbids it.
+    // Bit 7 is kept in P1 entry since it's PAT there.
+    fn effective_flags(parent: EntryFlags, entry: &Entry, level: usize) -> EntryFlags {
+        let mut flags = entry.flags();
+        flags.remove(EntryFlags::ACCESSED | EntryFlags::DIRTY);
+        if level == 2 || level == 3 {
+            flags.remove(EntryFlags::HUGE_PAGE);
+        }
+        flags.remove((EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE) & !parent);
+        flags.insert(parent & EntryFlags::NO_EXECUTE);
+        flags
+    }
+
+    // Visit mapped ranges of the active table in address order (except the recursive entry)
+    pub fn for_each_mapped_range<F: FnMut(&MappedRange)>(mut f: F) {
+        let mut current: Option<MappedRange> = None;
+        let pat = read_pat();
+        let mut visit = |number: u64, entry: &Entry, parent: EntryFlags, size: PageSize| {
+            let start = Page::from_number(number).start_address();
+            let range = MappedRange {
+                start,
+                end: start + size.bytes(),
+                phys: match size {
+                    PageSize::Size4KiB => entry.address(),
+                    _ => entry.huge_address(),
+                },
+                size,
+                flags: effective_flags(parent, entry, size.level()),
+                memory_type: memory_type(pat, entry, size),
+            };
+            let extended = match current.as_mut() {
+                Some(current) => current.extend(&range),
+                None => false,
+            };
+            if !extended {
+                if let Some(previous) = current.replace(range) {
+                    f(&previous);
+                }
+            }
+        };
+
+        let root = EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+        let p4 = get_p4_table();
+        for i4 in (0..TABLE_SIZE).filter(|&i| i != RECURSIVE_INDEX && p4[i].is_present()) {
+            let flags4 = effective_flags(root, &p4[i4], 4);
+            let p3 = get_child_table(p4, i4).unwrap();
+            for i3 in (0..TABLE_SIZE).filter(|&i| p3[i].is_present()) {
+                let number3 = (i4 as u64) << 27 | (i3 as u64) << 18;
+                if p3[i3].is_huge() {
+                    visit(number3, &p3[i3], flags4, PageSize::Size1GiB);
+                    continue;
+                }
+                let flags3 = effective_flags(flags4, &p3[i3], 3);
+                let p2 = get_child_table(p3, i3).unwrap();
+                for i2 in (0..TABLE_SIZE).filter(|&i| p2[i].is_present()) {
+                    let number2 = number3 | (i2 as u64) << 9;
+                    if p2[i2].is_huge() {
+                        visit(number2, &p2[i2], flags3, PageSize::Size2MiB);
+                        continue;
+                    }
+                    let flags2 = effective_flags(flags3, &p2[i2], 2);
+                    let p1 = get_child_table(p2, i2).unwrap();
+                    for i1 in (0..TABLE_SIZE).filter(|&i| p1[i].is_present()) {
+                        visit(number2 | i1 as u64, &p1[i1], flags2, PageSize::Size4KiB);
+                    }
+                }
+            }
+        }
+        if let Some(last) = current {
+            f(&last);
+        }
+    }
+
+    // Print coalesced mapped ranges (e.g. from a debug shell or a panic handler)
+    pub fn dump_page_table<W: fmt::Write>(w: &mut W) -> fmt::Result {
+        let mut result = Ok(());
+        for_each_mapped_range(|range| {
+            if result.is_ok() {
+                result = writeln!(w, "{}", range);
+            }
+        });
+        result
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum MapToError {
+        FrameAllocationFailed,
+        PageAlreadyMapped,
+        ParentEntryHugePage,
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum UnmapError {
+        PageNotMapped,
+        ParentEntryHugePage,
+        // e.g. `unmap_huge_page` with 2MB on 4KB pages
+        PageSizeMismatch,
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum TranslateError {
+        PageNotMapped,
+        ParentEntryHugePage,
+    }
+
+    impl From<TranslateError> for UnmapError {
+        fn from(error: TranslateError) -> Self {
+            match error {
+                TranslateError::PageNotMapped => UnmapError::PageNotMapped,
+                TranslateError::ParentEntryHugePage => UnmapError::ParentEntryHugePage,
+            }
+        }
+    }
+
+    // Child table of present entry (or error if it's a huge page or not present)
+    fn get_child_table_checked<'a, 'b>(
+        parent: &'a Table,
+        index: usize,
+    ) -> Result<&'b mut Table, TranslateError> {
+        if parent[index].is_huge() {
+            return Err(TranslateError::ParentEntryHugePage);
+        }
+        get_child_table(parent, index).ok_or(TranslateError::PageNotMapped)
+    }
+
+    fn zero_page(page: Page) {
+        let addr = page.start_address().as_u64() as usize;
+        let page_data = unsafe { address_cast_mut::<[u8; PAGE_SIZE as usize]>(addr) };
+        page_data.iter_mut().for_each(|x| *x = 0);
+    }
+
+    // Through the physical memory mapping, so it works for inactive tables as well
+    fn zero_frame(frame: Frame) {
+        zero_page(Page::containing_address(phys_to_virt(
+            frame.start_address(),
+        )));
+    }
+
+    pub fn initialize_page(page: Page) -> Result<(), TranslateError> {
+        translate_page(page)?;
+        zero_page(page);
+        Ok(())
+    }
+
+    pub fn get_or_create_child_table<'a, 'b, 'c, A: FrameAllocator + ?Sized>(
+        parent: &'a mut Table,
+        index: usize,
+        flags: EntryFlags,
+        allocator: &'b mut A,
+    ) -> Result<&'c mut Table, MapToError> {
+        if parent[index].is_huge() {
+            return Err(MapToError::ParentEntryHugePage);
+        }
+        if let Some(child) = get_child_table(parent, index) {
+            let mut parent_flags = parent[index].flags();
+            parent_flags.insert(flags);
+            parent[index].set_flags(parent_flags);
+            return Ok(child);
+        }
+        let frame = allocator
+            .allocate()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        zero_frame(frame);
+        parent[index].set(frame, flags);
+        Ok(get_child_table(parent, index).unwrap())
+    }
+
+    // TLB entry has to be invalidated after changing page table entry
+    #[must_use = "page table change has to be flushed or explicitly ignored"]
+    pub struct MapperFlush(Page);
+
+    impl MapperFlush {
+        fn new(page: Page) -> Self {
+            Self(page)
+        }
+
+        pub fn page(&self) -> Page {
+            self.0
+        }
+
+        pub fn flush(self) {
+            invlpg(self.0.start_address().as_u64());
+        }
+
+        // e.g. when the change is made on an inactive table
+        pub fn ignore(self) {}
+    }
+
+    // Leaf entry of 4KB page (the entry itself might be unused)
+    pub fn get_page_entry<'a>(page: Page) -> Result<&'a mut Entry, TranslateError> {
+        let p4 = get_p4_table();
+        let p3 = get_child_table_checked(p4, page.p4_index())?;
+        let p2 = get_child_table_checked(p3, page.p3_index())?;
+        let p1 = get_child_table_checked(p2, page.p2_index())?;
+        Ok(&mut p1[page.p1_index()])
+    }
+
+    // Frame of 4KB page
+    pub fn translate_page(page: Page) -> Result<Frame, TranslateError> {
+        get_page_entry(page)?
+            .frame()
+            .ok_or(TranslateError::PageNotMapped)
+    }
+
+    // Map page to frame and zero it
+    pub fn map_page_to_frame<'a, A: FrameAllocator + ?Sized>(
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+        allocator: &'a mut A,
+    ) -> Result<MapperFlush, MapToError> {
+        zero_frame(frame);
+        map_page_to_existing_frame(page, frame, flags, allocator)
+    }
+
+    // Map page to frame while keeping its content
+    pub fn map_page_to_existing_frame<'a, A: FrameAllocator + ?Sized>(
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+        allocator: &'a mut A,
+    ) -> Result<MapperFlush, MapToError> {
+        // User access has to be allowed on every level
+        let table_flags = TABLE_FLAGS | (flags & EntryFlags::USER_ACCESSIBLE);
+        let p4 = get_p4_table();
+        let p3 = get_or_create_child_table(p4, page.p4_index(), table_flags, allocator)?;
+        let p2 = get_or_create_child_table(p3, page.p3_index(), table_flags, allocator)?;
+        let p1 = get_or_create_child_table(p2, page.p2_index(), table_flags, allocator)?;
+        let entry = &mut p1[page.p1_index()];
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        entry.set(frame, supported_flags(flags | EntryFlags::PRESENT));
+        Ok(MapperFlush::new(page))
+    }
+
+    // Change flags of mapped 4KB page
+    pub fn update_page_flags(page: Page, flags: EntryFlags) -> Result<MapperFlush, TranslateError> {
+        let entry = get_page_entry(page)?;
+        if !entry.is_present() {
+            return Err(TranslateError::PageNotMapped);
+        }
+        entry.set_flags(supported_flags(flags | EntryFlags::PRESENT));
+        Ok(MapperFlush::new(page))
+    }
+
+    // Map 2MB or 1GB page to the frame range starting from `frame` (memory is not initialized)
+    pub fn map_huge_page_to_frame<'a, A: FrameAllocator + ?Sized>(
+        page: Page,
+        frame: Frame,
+        size: PageSize,
+        flags: EntryFlags,
+        allocator: &'a mut A,
+    ) -> Result<MapperFlush, MapToError> {
+        assert!(page.number() % size.pages() == 0, "unaligned {:?}", page);
+        assert!(frame.number() % size.pages() == 0, "unaligned {:?}", frame);
+        let table_flags = TABLE_FLAGS | (flags & EntryFlags::USER_ACCESSIBLE);
+        let flags = supported_flags(flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+        let p4 = get_p4_table();
+        let p3 = get_or_create_child_table(p4, page.p4_index(), table_flags, allocator)?;
+        let entry = match size {
+            PageSize::Size4KiB => {
+                return map_page_to_frame(page, frame, flags & !EntryFlags::HUGE_PAGE, allocator)
+            }
+            PageSize::Size1GiB => {
+                assert!(is_1gib_page_supported(), "1GB page is not supported");
+                &mut p3[page.p3_index()]
+            }
+            PageSize::Size2MiB => {
+                let p2 = get_or_create_child_table(p3, page.p3_index(), table_flags, allocator)?;
+                &mut p2[page.p2_index()]
+            }
+        };
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        entry.set(frame, flags);
+        Ok(MapperFlush::new(page))
+    }
+
+    // Returns the first frame of the range (frames are not deallocated)
+    pub fn unmap_huge_page(page: Page, size: PageSize) -> Result<(Frame, MapperFlush), UnmapError> {
+        assert!(page.number() % size.pages() == 0, "unaligned {:?}", page);
+        let p4 = get_p4_table();
+        let p3 = get_child_table_checked(p4, page.p4_index())?;
+        let entry = match size {
+            PageSize::Size4KiB => return unmap_page(page),
+            PageSize::Size1GiB => &mut p3[page.p3_index()],
+            PageSize::Size2MiB => {
+                if p3[page.p3_index()].is_huge() {
+                    return Err(UnmapError::PageSizeMismatch);
+                }
+                let p2 = get_child_table_checked(p3, page.p3_index())?;
+                &mut p2[page.p2_index()]
+            }
+        };
+        if !entry.is_present() {
+            return Err(UnmapError::PageNotMapped);
+        }
+        if !entry.is_huge() {
+            return Err(UnmapError::PageSizeMismatch);
+        }
+        let frame = Frame::containing_address(entry.huge_address());
+        entry.set_unused();
+        Ok((frame, MapperFlush::new(page)))
+    }
+
+    // The frame must not be deallocated before the flush, since it may be handed out again
+    pub fn unmap_page(page: Page) -> Result<(Frame, MapperFlush), UnmapError> {
+        let entry = get_page_entry(page)?;
+        let frame = entry.frame().ok_or(UnmapError::PageNotMapped)?;
+        entry.set_unused();
+        Ok((frame, MapperFlush::new(page)))
+    }
+
+    // Map each page to newly allocated frame
+    pub fn map_page_range<'a, A: FrameAllocator + ?Sized>(
+        pages: PageRange,
+        flags: EntryFlags,
+        allocator: &'a mut A,
+    ) -> Result<(), MapToError> {
+        for page in pages {
+            let frame = allocator
+                .allocate()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            match map_page_to_frame(page, frame, flags, allocator) {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    allocator.deallocate(frame);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Copy-on-write
+    //   Writable page is shared as read only with COPY_ON_WRITE marker.
+    //   Write fault on such page copies the frame (or just restores WRITABLE if it's the last one).
+    //
+
+    fn copy_on_write_flags(flags: EntryFlags) -> EntryFlags {
+        let mut flags = flags;
+        if flags.contains(EntryFlags::WRITABLE) {
+            flags.remove(EntryFlags::WRITABLE);
+            flags.insert(EntryFlags::COPY_ON_WRITE);
+        }
+        flags
+    }
+
+    // Turn mapped page into copy-on-write. Returns its frame and new flags to share it with.
+    pub fn make_copy_on_write(
+        page: Page,
+    ) -> Result<(Frame, EntryFlags, MapperFlush), TranslateError> {
+        let entry = get_page_entry(page)?;
+        let frame = entry.frame().ok_or(TranslateError::PageNotMapped)?;
+        let flags = copy_on_write_flags(entry.flags());
+        entry.set_flags(flags);
+        Ok((frame, flags, MapperFlush::new(page)))
+    }
+
+    // Map page to the frame shared with other mappings (its reference count is incremented)
+    pub fn map_page_copy_on_write<'a, A: FrameAllocator + ?Sized>(
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+        allocator: &'a mut A,
+    ) -> Result<MapperFlush, MapToError> {
+        allocator.add_reference(frame);
+        let result = map_page_to_existing_frame(page, frame, copy_on_write_flags(flags), allocator);
+        if result.is_err() {
+            allocator.deallocate(frame);
+        }
+        result
+    }
+
+    pub fn is_copy_on_write(page: Page) -> bool {
+        get_page_entry(page).map_or(false, |entry| {
+            entry.is_present() && entry.flags().contains(EntryFlags::COPY_ON_WRITE)
+        })
+    }
+
+    // Page to reach the new frame while copying
+    const COPY_ON_WRITE_TEMPORARY_ADDRESS: VirtAddr =
+        VirtAddr::new_truncate(TEMPORARY_PAGE_REGION_START);
+
+    // Make copy-on-write page writable (called on write fault)
+    pub fn copy_on_write<'a, A: FrameAllocator + ?Sized>(
+        page: Page,
+        allocator: &'a mut A,
+    ) -> Result<MapperFlush, MapToError> {
+        assert!(is_copy_on_write(page), "{:?} is not copy-on-write", page);
+        let entry = get_page_entry(page).unwrap();
+        let frame = entry.frame().unwrap();
+        let mut flags = entry.flags();
+        flags.remove(EntryFlags::COPY_ON_WRITE);
+        flags.insert(EntryFlags::WRITABLE);
+
+        // Last reference takes over the frame
+        if allocator.reference_count(frame) <= 1 {
+            entry.set_flags(supported_flags(flags));
+            return Ok(MapperFlush::new(page));
+        }
+
+        let new_frame = allocator
+            .allocate()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let mut temporary_page =
+            TemporaryPage::new(Page::containing_address(COPY_ON_WRITE_TEMPORARY_ADDRESS));
+        let dst = match temporary_page.map(new_frame, allocator) {
+            Ok(dst) => dst,
+            Err(error) => {
+                allocator.deallocate(new_frame);
+                return Err(error);
+            }
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                page.start_address().as_ptr::<u8>(),
+                dst.as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            );
+        }
+        temporary_page.unmap();
+        entry.set(new_frame, supported_flags(flags));
+        allocator.deallocate(frame);
+        Ok(MapperFlush::new(page))
+    }
+
+    //
+    // Active/inactive page table
+    //   `ActivePageTable::with` temporarily points the recursive entry to the inactive table,
+    //   so that the same `Mapper` methods can edit another address space.
+    //
+
+    // Every method operates on the table which the recursive entry points to
+    pub struct Mapper {
+        _private: (),
+    }
+
+    impl Mapper {
+        pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageSize)> {
+            translate(addr)
+        }
+
+        pub fn virtual_to_physical(&self, addr: VirtAddr) -> Option<PhysAddr> {
+            virtual_to_physical(addr)
+        }
+
+        pub fn get_page_entry<'a>(
+            &'a mut self,
+            page: Page,
+        ) -> Result<&'a mut Entry, TranslateError> {
+            get_page_entry(page)
+        }
+
+        pub fn translate_page(&self, page: Page) -> Result<Frame, TranslateError> {
+            translate_page(page)
+        }
+
+        pub fn update_page_flags(
+            &mut self,
+            page: Page,
+            flags: EntryFlags,
+        ) -> Result<MapperFlush, TranslateError> {
+            update_page_flags(page, flags)
+        }
+
+        pub fn map_page_to_frame<A: FrameAllocator + ?Sized>(
+            &mut self,
+            page: Page,
+            frame: Frame,
+            flags: EntryFlags,
+            allocator: &mut A,
+        ) -> Result<MapperFlush, MapToError> {
+            map_page_to_frame(page, frame, flags, allocator)
+        }
+
+        pub fn map_page_to_existing_frame<A: FrameAllocator + ?Sized>(
+            &mut self,
+            page: Page,
+            frame: Frame,
+            flags: EntryFlags,
+            allocator: &mut A,
+        ) -> Result<MapperFlush, MapToError> {
+            map_page_to_existing_frame(page, frame, flags, allocator)
+        }
+
+        pub fn map_huge_page_to_frame<A: FrameAllocator + ?Sized>(
+            &mut self,
+            page: Page,
+            frame: Frame,
+            size: PageSize,
+            flags: EntryFlags,
+            allocator: &mut A,
+        ) -> Result<MapperFlush, MapToError> {
+            map_huge_page_to_frame(page, frame, size, flags, allocator)
+        }
+
+        pub fn unmap_page(&mut self, page: Page) -> Result<(Frame, MapperFlush), UnmapError> {
+            unmap_page(page)
+        }
+
+        pub fn unmap_huge_page(
+            &mut self,
+            page: Page,
+            size: PageSize,
+        ) -> Result<(Frame, MapperFlush), UnmapError> {
+            unmap_huge_page(page, size)
+        }
+
+        pub fn make_copy_on_write(
+            &mut self,
+            page: Page,
+        ) -> Result<(Frame, EntryFlags, MapperFlush), TranslateError> {
+            make_copy_on_write(page)
+        }
+
+        pub fn map_page_copy_on_write<A: FrameAllocator + ?Sized>(
+            &mut self,
+            page: Page,
+            frame: Frame,
+            flags: EntryFlags,
+            allocator: &mut A,
+        ) -> Result<MapperFlush, MapToError> {
+            map_page_copy_on_write(page, frame, flags, allocator)
+        }
+    }
+
+    pub struct ActivePageTable {
+        mapper: Mapper,
+    }
+
+    impl Deref for ActivePageTable {
+        type Target = Mapper;
+
+        fn deref(&self) -> &Mapper {
+            &self.mapper
+        }
+    }
+
+    impl DerefMut for ActivePageTable {
+        fn deref_mut(&mut self) -> &mut Mapper {
+            &mut self.mapper
+        }
+    }
+
+    impl ActivePageTable {
+        // There must be only one instance since it owns the current address space
+        pub unsafe fn new() -> Self {
+            Self {
+                mapper: Mapper { _private: () },
+            }
+        }
+
+        pub fn p4_frame(&self) -> Frame {
+            Frame::containing_address(PhysAddr::new(read_cr3() & ENTRY_ADDRESS_MASK))
+        }
+
+        // Run `f` with the mapper editing `table` instead of the active one
+        // (fails only when the temporary page cannot be mapped)
+        pub fn with<A, F, R>(
+            &mut self,
+            table: &mut InactivePageTable,
+            temporary_page: &mut TemporaryPage,
+            allocator: &mut A,
+            f: F,
+        ) -> Result<R, MapToError>
+        where
+            A: FrameAllocator + ?Sized,
+            F: FnOnce(&mut Mapper, &mut A) -> R,
+        {
+            // Keep the active p4 reachable while the recursive entry points elsewhere
+            let active_p4_frame = self.p4_frame();
+            let active_p4 = temporary_page.map_table_frame(active_p4_frame, allocator)?;
+
+            get_p4_table()[RECURSIVE_INDEX].set(table.p4_frame, TABLE_FLAGS);
+            flush_tlb();
+
+            let result = f(&mut self.mapper, allocator);
+
+            active_p4[RECURSIVE_INDEX].set(active_p4_frame, TABLE_FLAGS);
+            flush_tlb();
+
+            temporary_page.unmap();
+            Ok(result)
+        }
+
+        // Returns the previously active table
+        pub fn switch(&mut self, table: InactivePageTable) -> InactivePageTable {
+            let old = InactivePageTable {
+                p4_frame: self.p4_frame(),
+            };
+            write_cr3(table.p4_frame.start_address().as_u64());
+            old
+        }
+    }
+
+    pub struct InactivePageTable {
+        p4_frame: Frame,
+    }
+
+    impl InactivePageTable {
+        // Initialize empty p4 table (except recursive entry) on `frame`
+        pub fn new<A: FrameAllocator + ?Sized>(
+            frame: Frame,
+            temporary_page: &mut TemporaryPage,
+            allocator: &mut A,
+        ) -> Result<Self, MapToError> {
+            let table = temporary_page.map_table_frame(frame, allocator)?;
+            table.iter_mut().for_each(|entry| entry.set_unused());
+            table[RECURSIVE_INDEX].set(frame, TABLE_FLAGS);
+            temporary_page.unmap();
+            Ok(Self { p4_frame: frame })
+        }
+
+        pub fn p4_frame(&self) -> Frame {
+            self.p4_frame
+        }
+    }
+
+    // Page in the active table to reach arbitrary frame (e.g. p4 table of inactive table)
+    pub struct TemporaryPage {
+        page: Page,
+    }
+
+    impl TemporaryPage {
+        pub fn new(page: Page) -> Self {
+            Self { page }
+        }
+
+        pub fn map<A: FrameAllocator + ?Sized>(
+            &mut self,
+            frame: Frame,
+            allocator: &mut A,
+        ) -> Result<VirtAddr, MapToError> {
+            let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+            map_page_to_existing_frame(self.page, frame, flags, allocator)?.flush();
+            Ok(self.page.start_address())
+        }
+
+        pub fn map_table_frame<'a, A: FrameAllocator + ?Sized>(
+            &mut self,
+            frame: Frame,
+            allocator: &mut A,
+        ) -> Result<&'a mut Table, MapToError> {
+            let addr = self.map(frame, allocator)?;
+            Ok(unsafe { address_cast_mut(addr.as_u64() as usize) })
+        }
+
+        pub fn unmap(&mut self) {
+            let (_, flush) = unmap_page(self.page).expect("temporary page is not mapped");
+            flush.flush();
+        }
+    }
+
+    //
+    // Remap kernel with per-section permissions
+    //
+
+    // Pages shared by multiple sections get the most permissive flags
+    fn merge_flags(x: EntryFlags, y: EntryFlags) -> EntryFlags {
+        let mut flags = (x | y) & !EntryFlags::NO_EXECUTE;
+        flags.insert(x & y & EntryFlags::NO_EXECUTE);
+        flags
+    }
+
+    // Map physical range [lo, hi) at virtual addresses `offset` above it (0 for identity map)
+    fn map_range<A: FrameAllocator + ?Sized>(
+        mapper: &mut Mapper,
+        offset: u64,
+        lo: PhysAddr,
+        hi: PhysAddr,
+        flags: EntryFlags,
+        allocator: &mut A,
+    ) -> Result<(), MapToError> {
+        let start = Frame::containing_address(lo);
+        let end = Frame::containing_address(hi.align_up(PAGE_SIZE));
+        for frame in Frame::range(start, end) {
+            let addr = frame.start_address().as_u64() + offset;
+            let page = Page::containing_address(VirtAddr::new(addr));
+            // Not active yet, so nothing to flush
+            match mapper.get_page_entry(page) {
+                Ok(entry) if entry.is_present() => {
+                    let flags = merge_flags(entry.flags(), flags | EntryFlags::PRESENT);
+                    mapper.update_page_flags(page, flags).unwrap().ignore();
+                }
+                _ => mapper
+                    .map_page_to_existing_frame(page, frame, flags, allocator)?
+                    .ignore(),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn section_flags(section: &SectionHeader) -> EntryFlags {
+        let mut flags = EntryFlags::empty();
+        if section.is_writable() {
+            flags.insert(EntryFlags::WRITABLE);
+        }
+        if !section.is_executable() {
+            flags.insert(EntryFlags::NO_EXECUTE);
+        }
+        flags
+    }
+
+    // Page for `TemporaryPage` used during remap
+    const REMAP_TEMPORARY_ADDRESS: VirtAddr =
+        VirtAddr::new_truncate(TEMPORARY_PAGE_REGION_START + PAGE_SIZE);
+
+    // Build new page table which maps
+    //   - allocated ELF sections at their link addresses with their permissions,
+    //   - physical memory (writable, e.g. multiboot information, VGA text buffer and
+    //     frame allocator storage) at `PHYSICAL_MEMORY_OFFSET` with 2MB pages,
+    //   - `extra` ranges (writable) at identity,
+    // then switch to it. Returns the previous table.
+    pub fn remap_kernel<A, I>(
+        active: &mut ActivePageTable,
+        boot_info: &BootInfo,
+        extra: I,
+        allocator: &mut A,
+    ) -> Result<InactivePageTable, MapToError>
+    where
+        A: FrameAllocator + ?Sized,
+        I: Iterator<Item = (PhysAddr, PhysAddr)>,
+    {
+        let mut temporary_page =
+            TemporaryPage::new(Page::containing_address(REMAP_TEMPORARY_ADDRESS));
+        let frame = allocator
+            .allocate()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let mut table = InactivePageTable::new(frame, &mut temporary_page, allocator)?;
+
+        active.with(
+            &mut table,
+            &mut temporary_page,
+            allocator,
+            |mapper, allocator| {
+                let sections = boot_info.section_headers().unwrap();
+                for section in sections.filter(|s| s.is_allocated() && s.size > 0) {
+                    let lo = section.physical_address();
+                    let hi = lo + section.size;
+                    let offset = section.addr - lo.as_u64();
+                    map_range(mapper, offset, lo, hi, section_flags(&section), allocator)?;
+                }
+
+                let data_flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+                let size = PageSize::Size2MiB;
+                let start = Page::containing_address(VirtAddr::new(PHYSICAL_MEMORY_OFFSET));
+                for i in 0..PHYSICAL_MEMORY_SIZE / size.bytes() {
+                    let (page, frame) = (
+                        start + i * size.pages(),
+                        Frame::from_number(i * size.pages()),
+                    );
+                    mapper
+                        .map_huge_page_to_frame(page, frame, size, data_flags, allocator)?
+                        .ignore();
+                }
+                for (lo, hi) in extra {
+                    map_range(mapper, 0, lo, hi, data_flags, allocator)?;
+                }
+                Ok(())
+            },
+        )??;
+
+        // Make read only pages read only for kernel as well
+        write_cr0(read_cr0() | CR0_WRITE_PROTECT);
+        Ok(active.switch(table))
+    }
+}
+
+pub mod heap_allocator {
+    use crate::memory::paging::{map_page_to_frame, EntryFlags};
+    use crate::memory::{Page, SharedFrameAllocator, VirtAddr, PAGE_SIZE};
+    use crate::util::IrqSafeMutex;
+    use core::alloc::Layout;
+    use core::fmt;
+
+    pub struct SimpleHeapAllocator {
+        start: usize,
+        end: usize,
+    }
+
+    const ALLOC_ERROR: *mut u8 = core::ptr::null_mut();
+
+    pub const HEAP_PAGE_FLAGS: EntryFlags = EntryFlags::WRITABLE.union(EntryFlags::NO_EXECUTE);
+
+    fn ceil_align(addr: usize, align: usize) -> usize {
+        addr + (align - (addr % align)) % align
+    }
+
+    impl SimpleHeapAllocator {
+        pub const fn new(start: usize, end: usize) -> Self {
+            Self { start, end }
+        }
+
+        pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+            let start = ceil_align(self.start, layout.align());
+            let end = start + layout.size();
+            if self.end < end {
+                ALLOC_ERROR
+            } else {
+                self.start = end;
+                start as _
+            }
+        }
+    }
+
+    //
+    // Linked list allocator
+    //   free blocks are kept in a list sorted by address,
+    //   so that adjacent blocks can be coalesced on `dealloc`.
+    //
+
+    struct FreeBlock {
+        size: usize,
+        next: *mut FreeBlock,
+    }
+
+    const BLOCK_SIZE: usize = core::mem::size_of::<FreeBlock>();
+    const BLOCK_ALIGN: usize = core::mem::align_of::<FreeBlock>();
+
+    #[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+    pub struct HeapStats {
+        pub size: usize, // mapped bytes
+        pub free: usize,
+        pub free_blocks: usize,
+        pub largest_free_block: usize,
+    }
+
+    impl HeapStats {
+        pub fn used(&self) -> usize {
+            self.size - self.free
+        }
+    }
+
+    impl fmt::Display for HeapStats {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "heap: size = {}, used = {}, free = {} ({} blocks, largest = {})",
+                self.size,
+                self.used(),
+                self.free,
+                self.free_blocks,
+                self.largest_free_block
+            )
+        }
+    }
+
+    pub struct LinkedListHeapAllocator {
+        head: FreeBlock, // dummy head (size = 0)
+        start: usize,
+        end: usize,     // end of mapped region
+        ceiling: usize, // limit of growth
+        initialized: bool,
+        frame_allocator: Option<SharedFrameAllocator>,
+    }
+
+    // Blocks are only accessed through the allocator
+    unsafe impl Send for LinkedListHeapAllocator {}
+
+    // Every allocation is large enough to hold `FreeBlock` when it's freed
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = ceil_align(core::cmp::max(layout.size(), BLOCK_SIZE), BLOCK_ALIGN);
+        let align = core::cmp::max(layout.align(), BLOCK_ALIGN);
+        (size, align)
+    }
+
+    // Find allocation start within the block so that the leftovers on both sides can be a block
+    fn block_fit(lo: usize, hi: usize, size: usize, align: usize) -> Option<usize> {
+        let mut start = ceil_align(lo, align);
+        if start != lo && start - lo < BLOCK_SIZE {
+            start = ceil_align(lo + BLOCK_SIZE, align);
+        }
+        let end = start.checked_add(size)?;
+        if hi < end || (end != hi && hi - end < BLOCK_SIZE) {
+            return None;
+        }
+        Some(start)
+    }
+
+    impl LinkedListHeapAllocator {
+        // Fixed heap `start..end` which is already mapped
+        pub const fn new(start: usize, end: usize) -> Self {
+            Self {
+                head: FreeBlock {
+                    size: 0,
+                    next: core::ptr::null_mut(),
+                },
+                start,
+                end,
+                ceiling: end,
+                initialized: false,
+                frame_allocator: None,
+            }
+        }
+
+        // Heap grows from page aligned `start` by mapping pages on demand (up to `ceiling`)
+        pub const fn growable(start: usize, ceiling: usize) -> Self {
+            let mut heap = Self::new(start, start);
+            heap.ceiling = ceiling;
+            heap
+        }
+
+        pub fn init(&mut self, frame_allocator: SharedFrameAllocator) {
+            self.frame_allocator = Some(frame_allocator);
+        }
+
+        pub fn size(&self) -> usize {
+            self.end - self.start
+        }
+
+        pub fn stats(&mut self) -> HeapStats {
+            self.initialize();
+            let mut stats = HeapStats {
+                size: self.size(),
+                ..HeapStats::default()
+            };
+            let mut block = self.head.next;
+            while !block.is_null() {
+                let size = unsafe { (*block).size };
+                stats.free += size;
+                stats.free_blocks += 1;
+                stats.largest_free_block = core::cmp::max(stats.largest_free_block, size);
+                block = unsafe { (*block).next };
+            }
+            stats
+        }
+
+        // Heap memory is not mapped yet when `new` is called (e.g. `static` initializer)
+        fn initialize(&mut self) {
+            if self.initialized {
+                return;
+            }
+            self.initialized = true;
+            let start = ceil_align(self.start, BLOCK_ALIGN);
+            if start + BLOCK_SIZE <= self.end {
+                unsafe { self.insert(start, (self.end - start) / BLOCK_ALIGN * BLOCK_ALIGN) };
+            }
+        }
+
+        pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+            self.initialize();
+            let (size, align) = block_layout(layout);
+            let ptr = self.alloc_block(size, align);
+            if ptr != ALLOC_ERROR {
+                return ptr;
+            }
+            // Leave enough room for alignment and leftover block
+            if self.grow(size + align + BLOCK_SIZE) {
+                return self.alloc_block(size, align);
+            }
+            ALLOC_ERROR
+        }
+
+        fn alloc_block(&mut self, size: usize, align: usize) -> *mut u8 {
+            let mut prev: *mut FreeBlock = &mut self.head;
+            unsafe {
+                while !(*prev).next.is_null() {
+                    let block = (*prev).next;
+                    let lo = block as usize;
+                    let hi = lo + (*block).size;
+                    if let Some(start) = block_fit(lo, hi, size, align) {
+                        // Unlink block and put back leftovers
+                        let mut prev = prev;
+                        let next = (*block).next;
+                        (*prev).next = next;
+                        if lo < start {
+                            prev = Self::link(prev, lo, start - lo);
+                        }
+                        if start + size < hi {
+                            Self::link(prev, start + size, hi - (start + size));
+                        }
+                        return start as _;
+                    }
+                    prev = block;
+                }
+            }
+            ALLOC_ERROR
+        }
+
+        pub fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+            let (size, _) = block_layout(layout);
+            unsafe { self.insert(ptr as usize, size) };
+        }
+
+        // Map pages after the current end and add them as a free block
+        fn grow(&mut self, size: usize) -> bool {
+            let mut frame_allocator = match self.frame_allocator {
+                Some(frame_allocator) => frame_allocator.lock(),
+                None => return false,
+            };
+            let new_end = ceil_align(self.end + size, PAGE_SIZE as usize);
+            if self.ceiling < new_end {
+                return false;
+            }
+            let old_end = self.end;
+            while self.end < new_end {
+                let frame = match frame_allocator.allocate() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                let page = Page::containing_address(VirtAddr::new(self.end as u64));
+                match map_page_to_frame(page, frame, HEAP_PAGE_FLAGS, &mut *frame_allocator) {
+                    Ok(flush) => flush.flush(),
+                    Err(_) => {
+                        frame_allocator.deallocate(frame);
+                        break;
+                    }
+                }
+                self.end += PAGE_SIZE as usize;
+            }
+            if old_end < self.end {
+                unsafe { self.insert(old_end, self.end - old_end) };
+            }
+            self.end == new_end
+        }
+
+        // Write new block at `addr` right after `prev`
+        unsafe fn link(prev: *mut FreeBlock, addr: usize, size: usize) -> *mut FreeBlock {
+            let block = addr as *mut FreeBlock;
+            block.write(FreeBlock {
+                size,
+                next: (*prev).next,
+            });
+            (*prev).next = block;
+            block
+        }
+
+        // Insert block in address order and coalesce with neighbours
+        unsafe fn insert(&mut self, addr: usize, size: usize) {
+            let head: *mut FreeBlock = &mut self.head;
+            let mut prev = head;
+            while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+                prev = (*prev).next;
+            }
+            let block = Self::link(prev, addr, size);
+            let next = (*block).next;
+            if !next.is_null() && addr + size == next as usize {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+            if prev != head && (prev as usize) + (*prev).size == addr {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            }
+        }
+    }
+
+    //
+    // Rust global allocator interface
+    //
+    use core::alloc::GlobalAlloc;
+
+    pub struct MutexSimpleHeapAllocator(IrqSafeMutex<SimpleHeapAllocator>);
+
+    impl MutexSimpleHeapAllocator {
+        pub const fn new(start: usize, end: usize) -> Self {
+            Self(IrqSafeMutex::new(SimpleHeapAllocator::new(start, end)))
+        }
+    }
+
+    unsafe impl GlobalAlloc for MutexSimpleHeapAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.0.lock().alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    pub struct MutexLinkedListHeapAllocator(IrqSafeMutex<LinkedListHeapAllocator>);
 
-pub fn frame_to_address(frame: Frame) -> PhysicalAddress {
-    frame * PAGE_SIZE
-}
+    impl MutexLinkedListHeapAllocator {
+        pub const fn new(start: usize, end: usize) -> Self {
+            Self(IrqSafeMutex::new(LinkedListHeapAllocator::new(start, end)))
+        }
 
-pub fn adress_to_frame(addr: PhysicalAddress) -> Frame {
-    addr / PAGE_SIZE
-}
+        pub const fn growable(start: usize, ceiling: usize) -> Self {
+            Self(IrqSafeMutex::new(LinkedListHeapAllocator::growable(
+                start, ceiling,
+            )))
+        }
 
-pub trait FrameAllocator {
-    fn allocate(&mut self) -> Option<Frame>;
-}
+        pub fn init(&self, frame_allocator: SharedFrameAllocator) {
+            self.0.lock().init(frame_allocator);
+        }
 
-pub struct SimpleFrameAllocator<I1, I2> {
-    index: Frame,
-    usable: I1,
-    occupied: I2,
-    usable_max: u64,
-}
+        pub fn size(&self) -> usize {
+            self.0.lock().size()
+        }
 
-impl<I1, I2> SimpleFrameAllocator<I1, I2>
-where
-    I1: Iterator<Item = (u64, u64)> + Clone,
-    I2: Iterator<Item = (u64, u64)> + Clone,
-{
-    pub fn new(usable: I1, occupied: I2) -> Self {
-        let usable_max = usable.clone().map(|(_, hi)| hi).max().unwrap_or(0);
-        Self {
-            index: 0,
-            usable,
-            occupied,
-            usable_max,
+        pub fn stats(&self) -> HeapStats {
+            self.0.lock().stats()
+        }
+    }
+
+    unsafe impl GlobalAlloc for MutexLinkedListHeapAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.0.lock().alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.0.lock().dealloc(ptr, layout)
         }
     }
 }
 
-impl<I1, I2> FrameAllocator for SimpleFrameAllocator<I1, I2>
-where
-    I1: Iterator<Item = (u64, u64)> + Clone,
-    I2: Iterator<Item = (u64, u64)> + Clone,
-{
-    fn allocate(&mut self) -> Option<Frame> {
-        loop {
-            let index = self.index;
-            let addr_lo = frame_to_address(index);
-            let addr_hi = frame_to_address(index + 1);
-            if addr_lo >= self.usable_max {
-                return None;
+pub mod slab_allocator {
+    use crate::memory::heap_allocator::HEAP_PAGE_FLAGS;
+    use crate::memory::heap_allocator::{HeapStats, LinkedListHeapAllocator};
+    use crate::memory::paging::map_page_to_frame;
+    use crate::memory::{Page, SharedFrameAllocator, VirtAddr, PAGE_SIZE};
+    use crate::util::IrqSafeMutex;
+    use core::alloc::Layout;
+
+    // Size classes 8, 16, .., 2048
+    const MIN_SIZE_SHIFT: usize = 3;
+    const MAX_SIZE_SHIFT: usize = 11;
+    pub const NUM_CACHES: usize = MAX_SIZE_SHIFT - MIN_SIZE_SHIFT + 1;
+
+    const ALLOC_ERROR: *mut u8 = core::ptr::null_mut();
+
+    // Cache index for the layout (None if it's too large for slab)
+    fn cache_index(layout: Layout) -> Option<usize> {
+        let size = core::cmp::max(layout.size(), layout.align()).next_power_of_two();
+        let shift = core::cmp::max(size.trailing_zeros() as usize, MIN_SIZE_SHIFT);
+        if shift <= MAX_SIZE_SHIFT {
+            Some(shift - MIN_SIZE_SHIFT)
+        } else {
+            None
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, Default)]
+    pub struct SlabStats {
+        pub size: usize,
+        pub pages: usize,
+        pub total: usize,
+        pub used: usize,
+    }
+
+    struct FreeObject {
+        next: *mut FreeObject,
+    }
+
+    struct SlabCache {
+        free: *mut FreeObject,
+        stats: SlabStats,
+    }
+
+    impl SlabCache {
+        const fn new(size: usize) -> Self {
+            Self {
+                free: core::ptr::null_mut(),
+                stats: SlabStats {
+                    size,
+                    pages: 0,
+                    total: 0,
+                    used: 0,
+                },
             }
-            self.index += 1;
-            let usable = self
-                .usable
-                .clone()
-                .any(|(lo, hi)| lo <= addr_lo && addr_hi <= hi);
-            let occupied = self
-                .occupied
-                .clone()
-                .any(|(lo, hi)| lo <= addr_lo && addr_hi <= hi);
-            if !usable || occupied {
-                continue;
+        }
+
+        // Carve a fresh page into objects
+        fn add_page(&mut self, addr: usize) {
+            let size = self.stats.size;
+            for offset in (0..(PAGE_SIZE as usize)).step_by(size).rev() {
+                self.push((addr + offset) as _);
+            }
+            self.stats.pages += 1;
+            self.stats.total += (PAGE_SIZE as usize) / size;
+            self.stats.used += (PAGE_SIZE as usize) / size;
+        }
+
+        fn push(&mut self, ptr: *mut u8) {
+            let object = ptr as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: self.free }) };
+            self.free = object;
+            self.stats.used -= 1;
+        }
+
+        fn pop(&mut self) -> Option<*mut u8> {
+            if self.free.is_null() {
+                return None;
             }
-            return Some(index);
+            let object = self.free;
+            self.free = unsafe { (*object).next };
+            self.stats.used += 1;
+            Some(object as _)
         }
     }
-}
 
-pub mod paging {
-    use crate::memory::{
-        frame_to_address, Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress, PAGE_SIZE,
-    };
-    use crate::util::address_cast_mut;
+    pub struct SlabAllocator {
+        caches: [SlabCache; NUM_CACHES],
+        next_page: usize,
+        end: usize,
+        frame_allocator: Option<SharedFrameAllocator>,
+    }
 
-    // Page entry flag
-    pub const PRESENT: u64 = 1 << 0;
-    pub const WRITABLE: u64 = 1 << 1;
+    // Objects are only accessed through the allocator
+    unsafe impl Send for SlabAllocator {}
 
-    pub const TABLE_SIZE: usize = 1 << 9; // = 512 = 4096 / 8 = PAGE_SIZE / sizeof(Entry)
+    impl SlabAllocator {
+        // Slab pages are mapped on demand within `start..end`
+        pub const fn new(start: usize, end: usize) -> Self {
+            Self {
+                caches: [
+                    SlabCache::new(1 << 3),
+                    SlabCache::new(1 << 4),
+                    SlabCache::new(1 << 5),
+                    SlabCache::new(1 << 6),
+                    SlabCache::new(1 << 7),
+                    SlabCache::new(1 << 8),
+                    SlabCache::new(1 << 9),
+                    SlabCache::new(1 << 10),
+                    SlabCache::new(1 << 11),
+                ],
+                next_page: start,
+                end,
+                frame_allocator: None,
+            }
+        }
 
-    pub type Entry = u64;
-    pub type Table = [Entry; TABLE_SIZE];
+        pub fn init(&mut self, frame_allocator: SharedFrameAllocator) {
+            self.frame_allocator = Some(frame_allocator);
+        }
 
-    // --16-- --9-- --9-- --9-- --9-- --12--
-    // 177777  777   777   777   777   0000
-    const ACTIVE_P4_TABLE_ADDRESS: VirtualAddress = 0o177777_777_777_777_777_0000;
+        pub fn stats(&self) -> [SlabStats; NUM_CACHES] {
+            let mut stats = [SlabStats::default(); NUM_CACHES];
+            for (i, cache) in self.caches.iter().enumerate() {
+                stats[i] = cache.stats;
+            }
+            stats
+        }
 
-    pub fn get_p4_table<'a>() -> &'a mut Table {
-        unsafe { address_cast_mut(ACTIVE_P4_TABLE_ADDRESS as usize) }
+        pub fn alloc(&mut self, index: usize) -> *mut u8 {
+            if let Some(ptr) = self.caches[index].pop() {
+                return ptr;
+            }
+            match self.map_page() {
+                Some(addr) => {
+                    self.caches[index].add_page(addr);
+                    self.caches[index].pop().unwrap()
+                }
+                None => ALLOC_ERROR,
+            }
+        }
+
+        pub fn dealloc(&mut self, index: usize, ptr: *mut u8) {
+            self.caches[index].push(ptr);
+        }
+
+        fn map_page(&mut self) -> Option<usize> {
+            if self.end < self.next_page + PAGE_SIZE as usize {
+                return None;
+            }
+            let mut allocator = self.frame_allocator?.lock();
+            let frame = allocator.allocate()?;
+            let addr = self.next_page;
+            let page = Page::containing_address(VirtAddr::new(addr as u64));
+            match map_page_to_frame(page, frame, HEAP_PAGE_FLAGS, &mut *allocator) {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    allocator.deallocate(frame);
+                    return None;
+                }
+            }
+            self.next_page += PAGE_SIZE as usize;
+            Some(addr)
+        }
     }
 
-    pub fn get_child_table_address<'a>(parent: &'a Table, index: usize) -> VirtualAddress {
-        let p_addr = parent as *const _ as u64;
-        let c_addr = (p_addr | ((index as u64) << 3)) << 9;
-        c_addr
+    //
+    // Rust global allocator interface
+    //   small layouts are served by slab and the rest falls back to linked list heap
+    //
+    use core::alloc::GlobalAlloc;
+
+    pub struct MutexSlabHeapAllocator {
+        slab: IrqSafeMutex<SlabAllocator>,
+        heap: IrqSafeMutex<LinkedListHeapAllocator>,
     }
 
-    pub fn get_child_table<'a, 'b>(parent: &'a Table, index: usize) -> Option<&'b mut Table> {
-        if parent[index] & PRESENT == 0 {
-            return None;
+    impl MutexSlabHeapAllocator {
+        pub const fn new(
+            slab_start: usize,
+            slab_end: usize,
+            heap_start: usize,
+            heap_end: usize,
+        ) -> Self {
+            Self {
+                slab: IrqSafeMutex::new(SlabAllocator::new(slab_start, slab_end)),
+                heap: IrqSafeMutex::new(LinkedListHeapAllocator::new(heap_start, heap_end)),
+            }
+        }
+
+        pub fn init(&self, frame_allocator: SharedFrameAllocator) {
+            self.slab.lock().init(frame_allocator);
+            self.heap.lock().init(frame_allocator);
+        }
+
+        pub fn slab_stats(&self) -> [SlabStats; NUM_CACHES] {
+            self.slab.lock().stats()
+        }
+
+        // Linked list heap for layouts too large for slab
+        pub fn heap_stats(&self) -> HeapStats {
+            self.heap.lock().stats()
         }
-        let addr = get_child_table_address(parent, index);
-        Some(unsafe { address_cast_mut(addr as usize) })
     }
 
-    pub fn virtual_to_page(addr: VirtualAddress) -> Page {
-        // TODO: Exclude invalid sign extension
-        (addr >> 12) & 0o777_777_777_777
+    unsafe impl GlobalAlloc for MutexSlabHeapAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            match cache_index(layout) {
+                Some(index) => self.slab.lock().alloc(index),
+                None => self.heap.lock().alloc(layout),
+            }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            match cache_index(layout) {
+                Some(index) => self.slab.lock().dealloc(index, ptr),
+                None => self.heap.lock().dealloc(ptr, layout),
+            }
+        }
     }
+}
 
-    pub fn page_to_virtual(page: Page) -> VirtualAddress {
-        assert!(page <= 0o777_777_777_777);
-        let extension = if (page >> (4 * 9 - 1)) == 0 {
-            0
-        } else {
-            0o177777
-        };
-        (page | (extension << (4 * 9))) << 12
+pub mod stack_allocator {
+    use crate::asm::read_cr2;
+    use crate::gdt::DOUBLE_FAULT_IST_INDEX;
+    use crate::idt::{Idt, IdtIndex, IsrArg};
+    use crate::memory::paging::{map_page_to_frame, unmap_page, EntryFlags};
+    use crate::memory::{FrameAllocator, Page, VirtAddr, PAGE_SIZE};
+    use crate::util::IrqSafeMutex;
+
+    // Kernel stacks are placed in fixed size slots of this region, so a freed slot can be
+    // reused by any stack. Each stack starts right after an unmapped guard page.
+    //   | guard | stack 0 | ... | guard | stack 1 | ... |
+    pub const STACK_REGION_START: VirtAddr = VirtAddr::new_truncate(0xffff_a000_0000_0000);
+    pub const STACK_REGION_END: VirtAddr = VirtAddr::new_truncate(0xffff_a000_1000_0000);
+
+    pub const MAX_STACKS: usize = 64;
+    const STACK_SLOT_SIZE: u64 =
+        (STACK_REGION_END.as_u64() - STACK_REGION_START.as_u64()) / MAX_STACKS as u64;
+    pub const MAX_STACK_PAGES: u64 = STACK_SLOT_SIZE / PAGE_SIZE - 1;
+
+    const STACK_PAGE_FLAGS: EntryFlags = EntryFlags::WRITABLE.union(EntryFlags::NO_EXECUTE);
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct Stack {
+        index: usize,
+        top: VirtAddr,
+        bottom: VirtAddr,
     }
 
-    pub fn page_p4_index(page: Page) -> usize {
-        ((page >> (9 * 3)) & 0o777) as usize
+    impl Stack {
+        pub fn index(&self) -> usize {
+            self.index
+        }
+
+        // Initial stack pointer (stack grows downwards)
+        pub fn top(&self) -> VirtAddr {
+            self.top
+        }
+
+        pub fn bottom(&self) -> VirtAddr {
+            self.bottom
+        }
+
+        pub fn guard_page(&self) -> Page {
+            Page::containing_address(self.bottom - PAGE_SIZE)
+        }
     }
 
-    pub fn page_p3_index(page: Page) -> usize {
-        ((page >> (9 * 2)) & 0o777) as usize
+    pub struct StackAllocator {
+        stacks: [Option<Stack>; MAX_STACKS],
     }
 
-    pub fn page_p2_index(page: Page) -> usize {
-        ((page >> (9 * 1)) & 0o777) as usize
+    impl StackAllocator {
+        pub const fn new() -> Self {
+            Self {
+                stacks: [None; MAX_STACKS],
+            }
+        }
+
+        // Map `num_pages` pages right after the guard page of the first free slot
+        pub fn allocate<A: FrameAllocator + ?Sized>(
+            &mut self,
+            num_pages: u64,
+            allocator: &mut A,
+        ) -> Option<Stack> {
+            assert!(num_pages > 0);
+            if num_pages > MAX_STACK_PAGES {
+                return None;
+            }
+            let index = self.stacks.iter().position(|stack| stack.is_none())?;
+            let bottom = STACK_REGION_START + index as u64 * STACK_SLOT_SIZE + PAGE_SIZE;
+            let top = bottom + num_pages * PAGE_SIZE;
+            let pages = Page::range(
+                Page::containing_address(bottom),
+                Page::containing_address(top),
+            );
+            for page in pages {
+                let mapped = match allocator.allocate() {
+                    Some(frame) => {
+                        match map_page_to_frame(page, frame, STACK_PAGE_FLAGS, allocator) {
+                            Ok(flush) => {
+                                flush.flush();
+                                true
+                            }
+                            Err(_) => {
+                                allocator.deallocate(frame);
+                                false
+                            }
+                        }
+                    }
+                    None => false,
+                };
+                if !mapped {
+                    let mapped_pages = Page::range(Page::containing_address(bottom), page);
+                    Self::unmap(mapped_pages, allocator);
+                    return None;
+                }
+            }
+            let stack = Stack { index, top, bottom };
+            self.stacks[index] = Some(stack);
+            Some(stack)
+        }
+
+        pub fn deallocate<A: FrameAllocator + ?Sized>(&mut self, stack: Stack, allocator: &mut A) {
+            assert!(
+                self.stacks[stack.index] == Some(stack),
+                "invalid stack {}",
+                stack.index
+            );
+            let pages = Page::range(
+                Page::containing_address(stack.bottom),
+                Page::containing_address(stack.top),
+            );
+            Self::unmap(pages, allocator);
+            self.stacks[stack.index] = None;
+        }
+
+        fn unmap<A: FrameAllocator + ?Sized>(pages: impl Iterator<Item = Page>, allocator: &mut A) {
+            for page in pages {
+                let (frame, flush) = unmap_page(page).unwrap();
+                flush.flush();
+                allocator.deallocate(frame);
+            }
+        }
+
+        // Index of the stack whose guard page contains `addr`
+        pub fn find_guard_page(&self, addr: VirtAddr) -> Option<usize> {
+            let page = Page::containing_address(addr);
+            self.stacks
+                .iter()
+                .flatten()
+                .find(|stack| stack.guard_page() == page)
+                .map(|stack| stack.index)
+        }
     }
 
-    pub fn page_p1_index(page: Page) -> usize {
-        ((page >> (9 * 0)) & 0o777) as usize
+    // Locked from fault handlers, so the holder must not be interrupted
+    pub static KERNEL_STACKS: IrqSafeMutex<StackAllocator> =
+        IrqSafeMutex::new(StackAllocator::new());
+
+    // Page fault handler can tell stack overflow from the faulting address (cr2)
+    pub fn check_stack_overflow(addr: VirtAddr) -> Option<usize> {
+        KERNEL_STACKS.lock().find_guard_page(addr)
     }
 
-    pub fn virtual_to_physical(addr: VirtualAddress) -> Option<PhysicalAddress> {
-        let offset = addr % PAGE_SIZE;
-        let page = virtual_to_page(addr);
-        let p4 = get_p4_table();
-        let p3 = get_child_table(p4, page_p4_index(page))?;
-        let p2 = get_child_table(p3, page_p3_index(page))?;
-        let p1 = get_child_table(p2, page_p2_index(page))?;
-        let entry = p1[page_p1_index(page)];
-        let phys_addr = entry & !(PAGE_SIZE - 1) + offset;
-        Some(phys_addr)
+    // Page fault caused by stack overflow cannot push its frame to the overflowing stack
+    // either, so it escalates to double fault, which runs on its own IST stack
+    extern "C" fn double_fault_handler(arg: &mut IsrArg) {
+        let addr = VirtAddr::new_truncate(read_cr2());
+        let error_code = arg.error_code;
+        if let Some(index) = check_stack_overflow(addr) {
+            panic!("stack overflow in stack {}", index);
+        }
+        panic!("double fault at {:?} (error_code = {})", addr, error_code);
     }
 
-    pub fn initialize_page(page: Page) {
-        let addr = page_to_virtual(page);
-        assert!(virtual_to_physical(addr) != None);
-        let page_data = unsafe { address_cast_mut::<[u8; PAGE_SIZE as usize]>(addr as usize) };
-        page_data.iter_mut().for_each(|x| *x = 0);
+    // IST stack is set by `gdt::init`
+    pub fn set_double_fault_handler(idt: &mut Idt) {
+        idt.set_handler(
+            IdtIndex::DoubleFault,
+            crate::make_isr!(double_fault_handler, has_error_code),
+        );
+        idt.set_ist(IdtIndex::DoubleFault, DOUBLE_FAULT_IST_INDEX);
     }
+}
 
-    pub fn get_or_create_child_table<'a, 'b, 'c, A: FrameAllocator>(
-        parent: &'a mut Table,
-        index: usize,
-        allocator: &'b mut A,
-    ) -> &'c mut Table {
-        if let Some(child) = get_child_table(parent, index) {
-            return child;
+pub mod vma {
+    use crate::asm::read_cr2;
+    use crate::idt::{Idt, IdtIndex, IsrArg};
+    use crate::memory::paging::{
+        copy_on_write, is_copy_on_write, map_page_to_frame, unmap_page, EntryFlags, MapToError,
+    };
+    use crate::memory::stack_allocator::check_stack_overflow;
+    use crate::memory::{Page, SharedFrameAllocator, VirtAddr, PAGE_SIZE};
+    use crate::util::IrqSafeMutex;
+
+    // Page fault error code (cf. https://wiki.osdev.org/Exceptions#Page_Fault)
+    pub const PAGE_FAULT_PRESENT: u64 = 1 << 0; // protection violation (otherwise not present)
+    pub const PAGE_FAULT_WRITE: u64 = 1 << 1;
+    pub const PAGE_FAULT_USER: u64 = 1 << 2;
+    pub const PAGE_FAULT_RESERVED_WRITE: u64 = 1 << 3; // reserved bit set in some entry
+    pub const PAGE_FAULT_INSTRUCTION_FETCH: u64 = 1 << 4;
+
+    pub const MAX_VMAS: usize = 32;
+
+    // Virtual memory area whose pages are mapped to zero-filled frames on first access
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct Vma {
+        pub start: VirtAddr,
+        pub end: VirtAddr,
+        pub flags: EntryFlags,
+    }
+
+    impl Vma {
+        pub fn contains(&self, addr: VirtAddr) -> bool {
+            self.start <= addr && addr < self.end
+        }
+
+        fn overlaps(&self, other: &Vma) -> bool {
+            self.start < other.end && other.start < self.end
+        }
+
+        fn allows(&self, error_code: u64) -> bool {
+            if error_code & PAGE_FAULT_WRITE != 0 && !self.flags.contains(EntryFlags::WRITABLE) {
+                return false;
+            }
+            if error_code & PAGE_FAULT_USER != 0
+                && !self.flags.contains(EntryFlags::USER_ACCESSIBLE)
+            {
+                return false;
+            }
+            if error_code & PAGE_FAULT_INSTRUCTION_FETCH != 0
+                && self.flags.contains(EntryFlags::NO_EXECUTE)
+            {
+                return false;
+            }
+            true
         }
-        let addr = get_child_table_address(parent, index);
-        let page = virtual_to_page(addr);
-        let frame = allocator.allocate().unwrap();
-        parent[index] = frame_to_address(frame) | PRESENT | WRITABLE;
-        initialize_page(page);
-        get_child_table(parent, index).unwrap()
     }
 
-    pub fn map_page_to_frame<'a, A: FrameAllocator>(
-        page: Page,
-        frame: Frame,
-        allocator: &'a mut A,
-    ) {
-        let p4 = get_p4_table();
-        let p3 = get_or_create_child_table(p4, page_p4_index(page), allocator);
-        let p2 = get_or_create_child_table(p3, page_p3_index(page), allocator);
-        let p1 = get_or_create_child_table(p2, page_p2_index(page), allocator);
-        p1[page_p1_index(page)] = frame_to_address(frame) | PRESENT | WRITABLE;
-        initialize_page(page);
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum VmaError {
+        Unaligned,
+        Overlap,
+        TooManyAreas,
     }
 
-    pub fn unmap_page(page: Page) {
-        let addr = page_to_virtual(page);
-        assert!(virtual_to_physical(addr) != None);
-        let p4 = get_p4_table();
-        let p3 = get_child_table(p4, page_p4_index(page)).unwrap();
-        let p2 = get_child_table(p3, page_p3_index(page)).unwrap();
-        let p1 = get_child_table(p2, page_p2_index(page)).unwrap();
-        p1[page_p1_index(page)] = 0;
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum PageFaultError {
+        NoArea,
+        // e.g. write to read only area or protection violation of present page
+        AccessViolation,
+        NoFrameAllocator,
+        MapFailed(MapToError),
+    }
+
+    pub struct VmaRegistry {
+        areas: [Option<Vma>; MAX_VMAS],
+        frame_allocator: Option<SharedFrameAllocator>,
+        handled_faults: u64,
+    }
+
+    impl VmaRegistry {
+        pub const fn new() -> Self {
+            Self {
+                areas: [None; MAX_VMAS],
+                frame_allocator: None,
+                handled_faults: 0,
+            }
+        }
+
+        pub fn init(&mut self, frame_allocator: SharedFrameAllocator) {
+            self.frame_allocator = Some(frame_allocator);
+        }
+
+        pub fn handled_faults(&self) -> u64 {
+            self.handled_faults
+        }
+
+        // Declare [start, end) without mapping anything yet
+        pub fn register(
+            &mut self,
+            start: VirtAddr,
+            end: VirtAddr,
+            flags: EntryFlags,
+        ) -> Result<(), VmaError> {
+            if !start.is_aligned(PAGE_SIZE) || !end.is_aligned(PAGE_SIZE) || end <= start {
+                return Err(VmaError::Unaligned);
+            }
+            let vma = Vma { start, end, flags };
+            if self.areas.iter().flatten().any(|area| area.overlaps(&vma)) {
+                return Err(VmaError::Overlap);
+            }
+            let slot = self
+                .areas
+                .iter_mut()
+                .find(|area| area.is_none())
+                .ok_or(VmaError::TooManyAreas)?;
+            *slot = Some(vma);
+            Ok(())
+        }
+
+        // Remove the area starting at `start` and release pages mapped so far
+        pub fn unregister(&mut self, start: VirtAddr) -> Option<Vma> {
+            let slot = self
+                .areas
+                .iter_mut()
+                .find(|area| area.map_or(false, |area| area.start == start))?;
+            let vma = slot.take()?;
+            if let Some(frame_allocator) = self.frame_allocator {
+                let mut allocator = frame_allocator.lock();
+                let pages = Page::range(
+                    Page::containing_address(vma.start),
+                    Page::containing_address(vma.end),
+                );
+                for page in pages {
+                    // Pages which were never touched are not mapped
+                    if let Ok((frame, flush)) = unmap_page(page) {
+                        flush.flush();
+                        allocator.deallocate(frame);
+                    }
+                }
+            }
+            Some(vma)
+        }
+
+        pub fn find(&self, addr: VirtAddr) -> Option<Vma> {
+            self.areas
+                .iter()
+                .flatten()
+                .find(|area| area.contains(addr))
+                .copied()
+        }
 
-        // TODO: deallocate frame
+        // Resolve write to copy-on-write page, or
+        // map zero-filled frame to the faulting page if the access is valid for its area
+        pub fn handle_page_fault(
+            &mut self,
+            addr: VirtAddr,
+            error_code: u64,
+        ) -> Result<(), PageFaultError> {
+            let page = Page::containing_address(addr);
+            let write_protection = PAGE_FAULT_PRESENT | PAGE_FAULT_WRITE;
+            if error_code & write_protection == write_protection && is_copy_on_write(page) {
+                let mut allocator = self
+                    .frame_allocator
+                    .ok_or(PageFaultError::NoFrameAllocator)?
+                    .lock();
+                copy_on_write(page, &mut *allocator)
+                    .map_err(PageFaultError::MapFailed)?
+                    .flush();
+                self.handled_faults += 1;
+                return Ok(());
+            }
+
+            let vma = self.find(addr).ok_or(PageFaultError::NoArea)?;
+            if error_code & PAGE_FAULT_PRESENT != 0 || !vma.allows(error_code) {
+                return Err(PageFaultError::AccessViolation);
+            }
+            let mut allocator = self
+                .frame_allocator
+                .ok_or(PageFaultError::NoFrameAllocator)?
+                .lock();
+            let frame = allocator
+                .allocate()
+                .ok_or(PageFaultError::MapFailed(MapToError::FrameAllocationFailed))?;
+            match map_page_to_frame(page, frame, vma.flags, &mut *allocator) {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    allocator.deallocate(frame);
+                    return Err(PageFaultError::MapFailed(error));
+                }
+            }
+            self.handled_faults += 1;
+            Ok(())
+        }
     }
 
-    pub fn map_page_range<'a, A: FrameAllocator>(start: Page, end: Page, allocator: &'a mut A) {
-        for page in start..end {
-            let frame = allocator.allocate().unwrap();
-            map_page_to_frame(page, frame, allocator);
+    // Locked from the page fault handler, so the holder must not be interrupted
+    pub static VMAS: IrqSafeMutex<VmaRegistry> = IrqSafeMutex::new(VmaRegistry::new());
+
+    // Resolve the fault from `VMAS` and resume, otherwise panic
+    extern "C" fn page_fault_handler(arg: &mut IsrArg) {
+        let addr = VirtAddr::new_truncate(read_cr2());
+        let error_code = arg.error_code;
+        if let Some(index) = check_stack_overflow(addr) {
+            panic!("stack overflow in stack {}", index);
         }
+        if let Err(error) = VMAS.lock().handle_page_fault(addr, error_code) {
+            panic!(
+                "page fault at {:?}: {:?} (error_code = {})",
+                addr, error, error_code
+            );
+        }
+    }
+
+    pub fn set_page_fault_handler(idt: &mut Idt) {
+        idt.set_handler(
+            IdtIndex::PageFault,
+            crate::make_isr!(page_fault_handler, has_error_code),
+        );
     }
 }
 
-pub mod heap_allocator {
+pub mod mmio {
+    use crate::asm::{cpuid, flush_tlb, rdmsr, wrmsr, IA32_PAT};
+    use crate::memory::paging::{map_page_to_existing_frame, unmap_page, EntryFlags, MapToError};
+    use crate::memory::{Frame, FrameAllocator, Page, PhysAddr, VirtAddr, PAGE_SIZE};
     use crate::util::Mutex;
-    use core::alloc::Layout;
 
-    pub struct SimpleHeapAllocator {
-        start: usize,
-        end: usize,
+    // Virtual window where device memory is mapped
+    pub const MMIO_REGION_START: VirtAddr = VirtAddr::new_truncate(0xffff_b000_0000_0000);
+    pub const MMIO_REGION_END: VirtAddr = VirtAddr::new_truncate(0xffff_b000_1000_0000);
+
+    // cf. https://en.wikipedia.org/wiki/Page_attribute_table
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum CacheType {
+        Uncached,
+        WriteThrough,
+        // Needs `enable_write_combining` (otherwise same as Uncached)
+        WriteCombining,
     }
 
-    const ALLOC_ERROR: *mut u8 = core::ptr::null_mut();
+    // PAT entry 5 (PAT + WRITE_THROUGH) is reprogrammed from write-through to write-combining
+    const PAT_WRITE_COMBINING_INDEX: u64 = 5;
+    const PAT_WRITE_COMBINING: u64 = 0x01;
 
-    fn ceil_align(addr: usize, align: usize) -> usize {
-        addr + (align - (addr % align)) % align
+    pub fn is_pat_supported() -> bool {
+        cpuid(1).edx & (1 << 16) != 0
     }
 
-    impl SimpleHeapAllocator {
-        pub const fn new(start: usize, end: usize) -> Self {
-            Self { start, end }
+    pub fn is_write_combining_enabled() -> bool {
+        is_pat_supported()
+            && (rdmsr(IA32_PAT) >> (PAT_WRITE_COMBINING_INDEX * 8)) & 0xff == PAT_WRITE_COMBINING
+    }
+
+    pub fn enable_write_combining() -> bool {
+        if !is_pat_supported() {
+            return false;
         }
+        let shift = PAT_WRITE_COMBINING_INDEX * 8;
+        let pat = rdmsr(IA32_PAT) & !(0xff << shift);
+        wrmsr(IA32_PAT, pat | (PAT_WRITE_COMBINING << shift));
+        flush_tlb();
+        true
+    }
 
-        pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
-            let start = ceil_align(self.start, layout.align());
-            let end = start + layout.size();
-            if self.end < end {
-                ALLOC_ERROR
-            } else {
-                self.start = end;
-                start as _
+    impl CacheType {
+        pub fn flags(self) -> EntryFlags {
+            match self {
+                CacheType::WriteCombining if is_write_combining_enabled() => {
+                    EntryFlags::p1_pat() | EntryFlags::WRITE_THROUGH
+                }
+                CacheType::Uncached | CacheType::WriteCombining => {
+                    EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH
+                }
+                CacheType::WriteThrough => EntryFlags::WRITE_THROUGH,
             }
         }
     }
 
-    //
-    // Rust global allocator interface
-    //
-    use core::alloc::GlobalAlloc;
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum MmioError {
+        WindowExhausted,
+        MapFailed(MapToError),
+    }
 
-    pub struct MutexSimpleHeapAllocator(Mutex<SimpleHeapAllocator>);
+    // Bump allocator of the window (unmapped range is not reused)
+    struct MmioWindow {
+        next: VirtAddr,
+    }
 
-    impl MutexSimpleHeapAllocator {
-        pub const fn new(start: usize, end: usize) -> Self {
-            Self(Mutex::new(SimpleHeapAllocator::new(start, end)))
+    static MMIO_WINDOW: Mutex<MmioWindow> = Mutex::new(MmioWindow {
+        next: MMIO_REGION_START,
+    });
+
+    fn frame_range(phys: PhysAddr, len: u64) -> (Frame, u64) {
+        let start = phys.align_down(PAGE_SIZE);
+        let end = (phys + len).align_up(PAGE_SIZE);
+        (Frame::containing_address(start), (end - start) / PAGE_SIZE)
+    }
+
+    // Map device memory [phys, phys + len) as uncached
+    pub fn map_mmio<A: FrameAllocator + ?Sized>(
+        phys: PhysAddr,
+        len: u64,
+        allocator: &mut A,
+    ) -> Result<VirtAddr, MmioError> {
+        map_mmio_with(phys, len, CacheType::Uncached, allocator)
+    }
+
+    // Frames are mapped as they are (i.e. `allocator` is only used for page tables),
+    // so they are never handed to the frame allocator even after `unmap_mmio`.
+    pub fn map_mmio_with<A: FrameAllocator + ?Sized>(
+        phys: PhysAddr,
+        len: u64,
+        cache: CacheType,
+        allocator: &mut A,
+    ) -> Result<VirtAddr, MmioError> {
+        assert!(len > 0);
+        let (start_frame, num_pages) = frame_range(phys, len);
+        let mut window = MMIO_WINDOW.lock();
+        let start = window.next;
+        if MMIO_REGION_END < start + num_pages * PAGE_SIZE {
+            return Err(MmioError::WindowExhausted);
+        }
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE | cache.flags();
+        let start_page = Page::containing_address(start);
+        for i in 0..num_pages {
+            let result =
+                map_page_to_existing_frame(start_page + i, start_frame + i, flags, allocator);
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    unmap_pages(start_page, i);
+                    return Err(MmioError::MapFailed(error));
+                }
+            }
         }
+        window.next = start + num_pages * PAGE_SIZE;
+        Ok(start + phys.as_u64() % PAGE_SIZE)
     }
 
-    unsafe impl GlobalAlloc for MutexSimpleHeapAllocator {
-        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-            self.0.lock().alloc(layout)
+    fn unmap_pages(start: Page, num_pages: u64) {
+        for page in Page::range(start, start + num_pages) {
+            let (_, flush) = unmap_page(page).expect("mmio page is not mapped");
+            flush.flush();
         }
+    }
 
-        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    // Unmap what `map_mmio` returned
+    pub fn unmap_mmio(virt: VirtAddr, len: u64) {
+        assert!(MMIO_REGION_START <= virt && virt < MMIO_REGION_END);
+        let start = virt.align_down(PAGE_SIZE);
+        let end = (virt + len).align_up(PAGE_SIZE);
+        unmap_pages(Page::containing_address(start), (end - start) / PAGE_SIZE);
     }
 }
@@ -1,5 +1,8 @@
 // cf. https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html
 
+use crate::memory::{virt_to_phys, PhysAddr, VirtAddr, KERNEL_OFFSET};
+use crate::memory::{MemoryMap, MemoryRegion, MemoryRegionKind};
+
 #[repr(C)]
 #[repr(packed)]
 #[derive(Debug, Copy, Clone)]
@@ -20,6 +23,7 @@ pub struct Tag {
 #[allow(dead_code)]
 pub enum TagType {
     End = 0,
+    Module = 3,
     MemoryMap = 6,
     Framebuffer = 8,
     SectionHeaderTable = 9,
@@ -35,6 +39,17 @@ pub struct MemoryMapTag {
     entry_version: u32,
 }
 
+#[repr(C)]
+#[repr(packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct ModuleTag {
+    type_: u32,
+    size: u32,
+    pub mod_start: u32,
+    pub mod_end: u32,
+    // followed by null terminated command line
+}
+
 #[repr(C)]
 #[repr(packed)]
 #[derive(Debug, Copy, Clone)]
@@ -88,17 +103,44 @@ pub struct SectionHeader {
     pub entsize: u64,
 }
 
+// Section flags
+const SHF_WRITE: u64 = 1 << 0;
+const SHF_ALLOC: u64 = 1 << 1;
+const SHF_EXECINSTR: u64 = 1 << 2;
+
+impl SectionHeader {
+    // Loaded as a part of the kernel image (e.g. debug information is not)
+    pub fn is_allocated(&self) -> bool {
+        self.flags & SHF_ALLOC != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & SHF_WRITE != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & SHF_EXECINSTR != 0
+    }
+
+    // Boot code (cf. linker.ld) and sections placed by the boot loader (e.g. symbols)
+    // have physical addresses, others are linked at `KERNEL_OFFSET`
+    pub fn physical_address(&self) -> PhysAddr {
+        PhysAddr::new(self.addr.checked_sub(KERNEL_OFFSET).unwrap_or(self.addr))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct TagIterator {
-    address: u32,
+    address: u64,
     offset: u32,
 }
 
 impl Iterator for TagIterator {
-    type Item = (Tag, u32);
+    type Item = (Tag, u64);
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let address = self.address + self.offset;
+            let address = self.address + self.offset as u64;
             let tag = unsafe { *(address as *const Tag) };
             if tag.type_ == TagType::End as _ {
                 break;
@@ -114,7 +156,7 @@ impl Iterator for TagIterator {
 #[derive(Debug, Copy, Clone)]
 pub struct MemoryMapIterator {
     tag: MemoryMapTag,
-    address: u32,
+    address: u64,
     offset: u32,
 }
 
@@ -123,7 +165,7 @@ impl Iterator for MemoryMapIterator {
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.offset < self.tag.size {
-            let item = unsafe { *((self.address + self.offset) as *const MemoryMapEntry) };
+            let item = unsafe { *((self.address + self.offset as u64) as *const MemoryMapEntry) };
             self.offset += self.tag.entry_size;
             return Some(item);
         }
@@ -134,7 +176,7 @@ impl Iterator for MemoryMapIterator {
 #[derive(Debug, Copy, Clone)]
 pub struct SectionHeaderIterator {
     tag: SectionHeaderTableTag,
-    address: u32,
+    address: u64,
     offset: u32,
 }
 
@@ -143,7 +185,7 @@ impl Iterator for SectionHeaderIterator {
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.offset < self.tag.size {
-            let item = unsafe { *((self.address + self.offset) as *const SectionHeader) };
+            let item = unsafe { *((self.address + self.offset as u64) as *const SectionHeader) };
             self.offset += self.tag.entsize;
             return Some(item);
         }
@@ -154,17 +196,23 @@ impl Iterator for SectionHeaderIterator {
 impl BootInfo {
     pub fn tags(&self) -> TagIterator {
         TagIterator {
-            address: self as *const _ as u32,
+            address: self as *const _ as u64,
             offset: 8,
         }
     }
 
-    pub fn find_tag<T: Copy>(&self, tag_type: TagType) -> Option<(T, u32)> {
+    pub fn find_tag<T: Copy>(&self, tag_type: TagType) -> Option<(T, u64)> {
         let (_, address) = self.tags().find(|(tag, _)| tag.type_ == tag_type as _)?;
         let tag = unsafe { *(address as *const T) };
         Some((tag, address))
     }
 
+    pub fn modules(&self) -> impl Iterator<Item = ModuleTag> + Clone {
+        self.tags()
+            .filter(|(tag, _)| tag.type_ == TagType::Module as _)
+            .map(|(_, address)| unsafe { *(address as *const ModuleTag) })
+    }
+
     pub fn framebuffer(&self) -> Option<FramebufferTag> {
         let (tag, _) = self.find_tag::<FramebufferTag>(TagType::Framebuffer)?;
         Some(tag)
@@ -190,23 +238,37 @@ impl BootInfo {
         })
     }
 
-    pub fn occupied_memory(&self) -> impl Iterator<Item = (u64, u64)> + Clone {
-        let x0 = self as *const _ as u64;
-        let x1 = x0 + (self.total_size as u64);
-        let section_headers = self.section_headers().unwrap().filter(|m| m.size > 0);
-        let y0 = section_headers.clone().map(|m| m.addr).min().unwrap();
-        let y1 = section_headers
-            .clone()
-            .map(|m| m.addr + m.size)
-            .max()
-            .unwrap();
-        crate::util::OwnedArrayIterator::new([(x0, x1), (y0, y1)])
+    // Physical range of this structure (`self` is reached through `PHYSICAL_MEMORY_OFFSET`)
+    pub fn memory_range(&self) -> (u64, u64) {
+        let lo = virt_to_phys(VirtAddr::from_ptr(self)).unwrap().as_u64();
+        (lo, lo + (self.total_size as u64))
     }
 
-    pub fn usable_memory(&self) -> impl Iterator<Item = (u64, u64)> + Clone {
-        self.memory_map()
-            .unwrap()
-            .filter(|m| m.type_ == 1)
-            .map(|m| (m.base_addr, m.base_addr + m.length))
+    // Firmware memory map with memory used by boot process (kernel image, this structure,
+    // modules and framebuffer) carved out of it
+    pub fn memory_regions(&self) -> MemoryMap {
+        let mut map = MemoryMap::new();
+        let region =
+            |lo: u64, hi: u64, kind| MemoryRegion::new(PhysAddr::new(lo), PhysAddr::new(hi), kind);
+        for m in self.memory_map().unwrap() {
+            let kind = MemoryRegionKind::from_multiboot(m.type_);
+            map.add(region(m.base_addr, m.base_addr + m.length, kind));
+        }
+        // Includes sections which are not allocated but loaded by the boot loader (e.g. symbols)
+        for m in self.section_headers().unwrap().filter(|m| m.size > 0) {
+            let lo = m.physical_address().as_u64();
+            map.add(region(lo, lo + m.size, MemoryRegionKind::Kernel));
+        }
+        let (lo, hi) = self.memory_range();
+        map.add(region(lo, hi, MemoryRegionKind::BootInfo));
+        for m in self.modules() {
+            let (lo, hi) = (m.mod_start as u64, m.mod_end as u64);
+            map.add(region(lo, hi, MemoryRegionKind::Module));
+        }
+        if let Some(fb) = self.framebuffer() {
+            let hi = fb.addr + (fb.pitch as u64) * (fb.height as u64);
+            map.add(region(fb.addr, hi, MemoryRegionKind::Framebuffer));
+        }
+        map
     }
 }
@@ -1,4 +1,4 @@
-use crate::asm::outb;
+use crate::asm::{inb, outb};
 
 // cf. https://wiki.osdev.org/PIC#Programming_with_the_8259_PIC
 
@@ -10,9 +10,11 @@ const PIC2_DATA_PORT: u16 = PIC2_COMMAND_PORT + 1;
 const PIC_INIT: u8 = 0x11;
 const PIC_8086: u8 = 0x01;
 const PIC_EOI: u8 = 0x20;
+const PIC_READ_ISR: u8 = 0x0b; // OCW3
 
-const PIC1_IDT_OFFSET: u8 = 32; // PIC0 uses 32..40
-const PIC2_IDT_OFFSET: u8 = 40; // PIC1 uses 40..48
+pub const PIC1_IDT_OFFSET: u8 = 32; // PIC0 uses 32..40
+pub const PIC2_IDT_OFFSET: u8 = 40; // PIC1 uses 40..48
+pub const NUM_IRQS: u8 = 16;
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
@@ -58,3 +60,44 @@ pub extern "C" fn pic1_eoi() {
 pub extern "C" fn pic2_eoi() {
     outb(PIC2_COMMAND_PORT, PIC_EOI);
 }
+
+// IRQ line raised on `vector` (None if it's not remapped PIC's range)
+pub fn irq_from_vector(vector: u8) -> Option<u8> {
+    if (PIC1_IDT_OFFSET..PIC1_IDT_OFFSET + NUM_IRQS).contains(&vector) {
+        Some(vector - PIC1_IDT_OFFSET)
+    } else {
+        None
+    }
+}
+
+// In-service register (bit `irq` is set while it's being handled)
+fn read_isr(command_port: u16) -> u8 {
+    outb(command_port, PIC_READ_ISR);
+    inb(command_port)
+}
+
+// cf. https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+// The lowest priority IRQ of each PIC is raised without being in service when the request
+// is withdrawn before it's acknowledged.
+pub fn is_spurious(irq: u8) -> bool {
+    match irq {
+        7 => read_isr(PIC1_COMMAND_PORT) & (1 << 7) == 0,
+        15 => read_isr(PIC2_COMMAND_PORT) & (1 << 7) == 0,
+        _ => false,
+    }
+}
+
+// Spurious IRQ 15 is still in service of PIC1 (as IRQ 2), so only PIC1 needs EOI
+pub fn spurious_eoi(irq: u8) {
+    if irq >= PIC2_IDT_OFFSET - PIC1_IDT_OFFSET {
+        pic1_eoi();
+    }
+}
+
+// IRQs from PIC2 are cascaded through PIC1, so both of them need EOI
+pub fn eoi(irq: u8) {
+    if irq >= PIC2_IDT_OFFSET - PIC1_IDT_OFFSET {
+        pic2_eoi();
+    }
+    pic1_eoi();
+}
@@ -1,6 +1,6 @@
 use crate::asm::{inb, outb};
 use crate::lazy_static;
-use crate::util::Mutex;
+use crate::util::IrqSafeMutex;
 use core::fmt;
 
 // cf. https://wiki.osdev.org/Serial_Ports
@@ -61,10 +61,10 @@ impl fmt::Write for SerialPort {
 }
 
 lazy_static! {
-    pub static ref SERIAL: Mutex<SerialPort> = {
+    pub static ref SERIAL: IrqSafeMutex<SerialPort> = {
         let mut serial = SerialPort { port: 0x3F8 };
         serial.init();
-        Mutex::new(serial)
+        IrqSafeMutex::new(serial)
     };
 }
 
@@ -73,80 +73,320 @@ pub unsafe fn reinterpret_cast_mut<'a, T1, T2>(reference: &mut T1) -> &'a mut T2
 }
 
 //
-// Fake Mutex to use static nicely
+// Spinlock
 //
 
-pub struct Mutex<T>(T);
+use crate::asm::{cli, read_rflags, sti, RFLAGS_IF};
+use core::cell::UnsafeCell;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[cfg(debug_assertions)]
+use core::panic::Location;
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicPtr;
+
+// Without threads, a held lock can be released only by the code it interrupted,
+// so spinning this long means a deadlock (e.g. IRQ handler locking what it interrupted)
+#[cfg(debug_assertions)]
+const DEADLOCK_SPINS: usize = 1 << 24;
+
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    #[cfg(debug_assertions)]
+    owner: AtomicPtr<Location<'static>>, // where the current guard was taken
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
 
 impl<T> Mutex<T> {
     pub const fn new(inner: T) -> Self {
-        Self(inner)
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            owner: AtomicPtr::new(core::ptr::null_mut()),
+            data: UnsafeCell::new(inner),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        let mut spins = 0;
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+                #[cfg(debug_assertions)]
+                {
+                    spins += 1;
+                    if spins == DEADLOCK_SPINS {
+                        self.deadlock();
+                    }
+                }
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        #[cfg(debug_assertions)]
+        self.owner
+            .store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // Only for the panic path, where the interrupted owner never resumes
+    pub unsafe fn force_unlock(&self) {
+        self.unlock();
+    }
+
+    fn unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(core::ptr::null_mut(), Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
     }
 
-    pub fn lock<'a>(&self) -> &'a mut T {
-        unsafe { &mut *(&self.0 as *const T as *mut T) }
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn deadlock(&self) -> ! {
+        let owner = self.owner.load(Ordering::Relaxed);
+        match unsafe { owner.as_ref() } {
+            Some(owner) => panic!("deadlock: mutex locked at {} is locked again", owner),
+            None => panic!("deadlock: mutex is locked again"),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
     }
 }
 
 //
-// Simple but unsafe lazy_static
+// Spinlock with interrupts disabled while held
+// (for data shared with interrupt handlers, e.g. printing from IRQ handler)
 //
 
-use core::cell::UnsafeCell;
-use core::mem::MaybeUninit;
+pub struct IrqSafeMutex<T: ?Sized>(Mutex<T>);
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(inner: T) -> Self {
+        Self(Mutex::new(inner))
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts_enabled = read_rflags() & RFLAGS_IF != 0;
+        cli();
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.0.lock()),
+            interrupts_enabled,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+
+    pub unsafe fn force_unlock(&self) {
+        self.0.force_unlock();
+    }
+}
 
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock before interrupts can come in
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            sti();
+        }
+    }
+}
+
+//
+// Once cell and lazy_static
+//
+
+const ONCE_INCOMPLETE: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_COMPLETE: u8 = 2;
+
+// Value is written in place, so its address is stable once initialized
 pub struct Once<T> {
+    state: AtomicU8,
     data: UnsafeCell<MaybeUninit<T>>,
-    done: bool,
 }
 
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
 impl<T> Once<T> {
-    pub const INIT: Self = Self {
-        data: UnsafeCell::new(MaybeUninit::uninit()),
-        done: false,
-    };
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_INCOMPLETE),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        self.call_once_then(f, |_| {})
+    }
 
-    pub fn call_once<F: Fn() -> T>(&mut self, f: F) -> &mut T {
-        if !self.done {
-            unsafe {
-                (*self.data.get()).as_mut_ptr().write(f());
+    // `then` runs on the value at its final address before other callers can see it
+    // (e.g. loading IDT whose address is given to the CPU)
+    pub fn call_once_then<F: FnOnce() -> T, G: FnOnce(&T)>(&self, f: F, then: G) -> &T {
+        match self.state.compare_exchange(
+            ONCE_INCOMPLETE,
+            ONCE_RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                let value = unsafe {
+                    let ptr = (*self.data.get()).as_mut_ptr();
+                    ptr.write(f());
+                    &*ptr
+                };
+                then(value);
+                self.state.store(ONCE_COMPLETE, Ordering::Release);
+                value
+            }
+            Err(_) => {
+                self.wait();
+                unsafe { self.get_unchecked() }
+            }
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            ONCE_COMPLETE => Some(unsafe { self.get_unchecked() }),
+            _ => None,
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ONCE_COMPLETE
+    }
+
+    unsafe fn get_unchecked(&self) -> &T {
+        &*(*self.data.get()).as_ptr()
+    }
+
+    // Same as `Mutex::lock`, initializer being interrupted by its own user cannot finish
+    fn wait(&self) {
+        #[cfg(debug_assertions)]
+        let mut spins = 0;
+        while self.state.load(Ordering::Acquire) == ONCE_RUNNING {
+            core::hint::spin_loop();
+            #[cfg(debug_assertions)]
+            {
+                spins += 1;
+                if spins == DEADLOCK_SPINS {
+                    panic!("deadlock: Once is accessed during its initialization");
+                }
             }
-            self.done = true;
         }
-        unsafe { &mut *(*self.data.get()).as_mut_ptr() }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == ONCE_COMPLETE {
+            unsafe { core::ptr::drop_in_place((*self.data.get()).as_mut_ptr()) };
+        }
     }
 }
 
 #[macro_export]
 macro_rules! lazy_static_impl {
-    (($($vis:tt)?) $N:ident : $T:ty = $e:expr) => {
-        use core::ops::Deref;
-        use $crate::util::Once;
-
+    (($($vis:tt)?) $N:ident : $T:ty = $e:expr => $then:expr) => {
         #[allow(non_camel_case_types)]
         #[allow(dead_code)]
         $($vis)? struct $N { __private_field: () }
         $($vis)? static $N: $N = $N { __private_field: () };
 
-        impl Deref for $N {
+        impl core::ops::Deref for $N {
             type Target = $T;
 
             fn deref(&self) -> &Self::Target {
-                static mut ONCE: Once<$T> = Once::INIT;
-                let closure = || { $e };
-                unsafe { ONCE.call_once(closure) }
+                static ONCE: $crate::util::Once<$T> = $crate::util::Once::new();
+                ONCE.call_once_then(|| $e, $then)
             }
         }
     };
 }
 
+// Optional `=> |value| ...` runs after the value is placed at its final address
 #[macro_export]
 macro_rules! lazy_static {
     (static ref $N:ident : $T:ty = $e:expr;) => {
-        $crate::lazy_static_impl!(() $N : $T = $e);
+        $crate::lazy_static_impl!(() $N : $T = $e => |_| {});
+    };
+
+    (static ref $N:ident : $T:ty = $e:expr => $then:expr;) => {
+        $crate::lazy_static_impl!(() $N : $T = $e => $then);
     };
 
     (pub static ref $N:ident : $T:ty = $e:expr;) => {
-        $crate::lazy_static_impl!((pub) $N : $T = $e);
-    }
+        $crate::lazy_static_impl!((pub) $N : $T = $e => |_| {});
+    };
+
+    (pub static ref $N:ident : $T:ty = $e:expr => $then:expr;) => {
+        $crate::lazy_static_impl!((pub) $N : $T = $e => $then);
+    };
 }
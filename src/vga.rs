@@ -1,5 +1,6 @@
 use crate::lazy_static;
-use crate::util::Mutex;
+use crate::memory::{phys_to_virt, PhysAddr};
+use crate::util::IrqSafeMutex;
 use crate::util::{address_cast_mut, Volatile};
 use core::fmt;
 
@@ -26,6 +27,8 @@ pub enum Color {
     White,
 }
 
+// Text mode buffer (reached through `PHYSICAL_MEMORY_OFFSET`)
+pub const BUFFER_ADDRESS: PhysAddr = PhysAddr::new_truncate(0xb8000);
 const BUFFER_WIDTH: usize = 80;
 const BUFFER_HEIGHT: usize = 25;
 type Buffer = [[Volatile<u16>; BUFFER_WIDTH]; BUFFER_HEIGHT];
@@ -126,10 +129,11 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = {
-        let mut writer = unsafe { Writer::from_address(0xb8000, Color::Gray, Color::Black) };
+    pub static ref WRITER: IrqSafeMutex<Writer> = {
+        let address = phys_to_virt(BUFFER_ADDRESS).as_u64() as usize;
+        let mut writer = unsafe { Writer::from_address(address, Color::Gray, Color::Black) };
         writer.clear();
-        Mutex::new(writer)
+        IrqSafeMutex::new(writer)
     };
 }
 